@@ -335,8 +335,36 @@ pub mod csr {
         }
     }
 
+    pub mod hvip {
+        use core::arch::asm;
+        pub const VSSIP: usize = 1 << 2;
+        pub const VSTIP: usize = 1 << 6;
+        pub const VSEIP: usize = 1 << 10;
+
+        pub fn read() -> usize {
+            let hvip: usize;
+            unsafe {
+                asm!(
+                    "csrr {}, hvip",
+                    out(reg) hvip
+                )
+            }
+            hvip
+        }
+
+        pub unsafe fn write(hvip: usize) {
+            asm!(
+                "csrw hvip, {}",
+                in(reg) hvip
+            )
+        }
+    }
+
     pub mod hcounteren {
         use core::arch::asm;
+        pub const CY: u32 = 1 << 0;
+        pub const TM: u32 = 1 << 1;
+        pub const IR: u32 = 1 << 2;
 
         pub unsafe fn write(hcounteren: u32) {
             asm!(
@@ -346,14 +374,204 @@ pub mod csr {
         }
     }
 
+    pub mod henvcfg {
+        use core::arch::asm;
+        /// let guest use `vstimecmp`(Sstc)
+        pub const STCE: usize = 1 << 63;
+
+        pub fn read() -> usize {
+            let henvcfg: usize;
+            unsafe {
+                // 0x60a => henvcfg
+                asm!(
+                    "csrr {}, 0x60a",
+                    out(reg) henvcfg
+                )
+            }
+            henvcfg
+        }
+
+        pub unsafe fn set(bits: usize) {
+            asm!(
+                "csrs 0x60a, {}",
+                in(reg) bits
+            )
+        }
+    }
+
+    pub mod htimedelta {
+        use core::arch::asm;
+
+        pub unsafe fn write(htimedelta: usize) {
+            asm!(
+                "csrw htimedelta, {}",
+                in(reg) htimedelta
+            )
+        }
+    }
+
+    pub mod vstimecmp {
+        use core::arch::asm;
+
+        pub fn read() -> usize {
+            let vstimecmp: usize;
+            unsafe {
+                // 0x24d => vstimecmp
+                asm!(
+                    "csrr {}, 0x24d",
+                    out(reg) vstimecmp
+                )
+            }
+            vstimecmp
+        }
+
+        pub unsafe fn write(vstimecmp: usize) {
+            // 0x24d => vstimecmp
+            asm!(
+                "csrw 0x24d, {}",
+                in(reg) vstimecmp
+            )
+        }
+    }
+
+    pub mod counter {
+        use core::arch::asm;
+
+        macro_rules! read_counter {
+            ($csr:expr, $($num:literal),+) => {
+                match $csr {
+                    $($num => {
+                        let value: usize;
+                        unsafe{ asm!(concat!("csrr {}, ", stringify!($num)), out(reg) value) };
+                        Some(value)
+                    })+
+                    _ => None
+                }
+            };
+        }
+
+        /// read `cycle`, `time`, `instret` or `hpmcounter3-31` by CSR number
+        pub fn read(csr: usize) -> Option<usize> {
+            read_counter!(
+                csr,
+                0xc00, 0xc01, 0xc02, 0xc03, 0xc04, 0xc05, 0xc06, 0xc07,
+                0xc08, 0xc09, 0xc0a, 0xc0b, 0xc0c, 0xc0d, 0xc0e, 0xc0f,
+                0xc10, 0xc11, 0xc12, 0xc13, 0xc14, 0xc15, 0xc16, 0xc17,
+                0xc18, 0xc19, 0xc1a, 0xc1b, 0xc1c, 0xc1d, 0xc1e, 0xc1f
+            )
+        }
+
+        /// whether `scounteren` lets U-mode read counter `csr`
+        pub fn user_enabled(csr: usize) -> bool {
+            let scounteren: usize;
+            unsafe{ asm!("csrr {}, scounteren", out(reg) scounteren) };
+            (0xc00..=0xc1f).contains(&csr) && scounteren & 1 << (csr - 0xc00) != 0
+        }
+    }
+
+    pub mod vsstatus {
+        use core::arch::asm;
+        pub const SIE: usize = 1 << 1;
+        pub const SPIE: usize = 1 << 5;
+        pub const SPP: usize = 1 << 8;
+        pub const SUM: usize = 1 << 18;
+        pub const MXR: usize = 1 << 19;
+
+        pub fn read() -> usize {
+            let vsstatus: usize;
+            unsafe {
+                asm!(
+                    "csrr {}, vsstatus",
+                    out(reg) vsstatus
+                )
+            }
+            vsstatus
+        }
+
+        pub unsafe fn write(vsstatus: usize) {
+            asm!(
+                "csrw vsstatus, {}",
+                in(reg) vsstatus
+            )
+        }
+    }
+
+    pub mod hgeie {
+        use core::arch::asm;
+
+        pub fn read() -> usize {
+            let hgeie: usize;
+            unsafe {
+                // 0x607 => hgeie
+                asm!(
+                    "csrr {}, 0x607",
+                    out(reg) hgeie
+                )
+            }
+            hgeie
+        }
+
+        pub unsafe fn write(hgeie: usize) {
+            asm!(
+                "csrw 0x607, {}",
+                in(reg) hgeie
+            )
+        }
+    }
+
+    pub mod hgeip {
+        use core::arch::asm;
+
+        pub fn read() -> usize {
+            let hgeip: usize;
+            unsafe {
+                // 0xe12 => hgeip
+                asm!(
+                    "csrr {}, 0xe12",
+                    out(reg) hgeip
+                )
+            }
+            hgeip
+        }
+    }
+
+    pub mod sie {
+        use core::arch::asm;
+        /// supervisor guest external interrupt enable
+        pub const SGEIE: usize = 1 << 12;
+
+        pub unsafe fn set(bits: usize) {
+            asm!(
+                "csrs sie, {}",
+                in(reg) bits
+            )
+        }
+    }
 
     pub mod sip {
+        use core::arch::asm;
+
+        pub fn read() -> usize {
+            let sip: usize;
+            unsafe {
+                asm!(
+                    "csrr {}, sip",
+                    out(reg) sip
+                )
+            }
+            sip
+        }
+
         /// software interrupts pending
         pub const SSIP: usize = 1 << 1;
         /// timer interrupts pending
         pub const STIP: usize = 1 << 5;
         /// external interrupts pending
         pub const SEIP: usize = 1 << 9;
+        /// supervisor guest external interrupts pending
+        pub const SGEIP: usize = 1 << 12;
+        /// `scause` code of supervisor guest external interrupt
+        pub const SGEI_CODE: usize = 12;
     }
 
 }
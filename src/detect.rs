@@ -19,6 +19,16 @@ pub fn detect_h_extension() -> bool {
     ans != 2
 }
 
+// Detect if Sstc extension exists and is enabled for supervisor mode by firmware
+//
+// This function tries to read stimecmp and returns false if the read operation failed.
+pub fn detect_sstc_extension() -> bool {
+    let ans = with_detect_trap(0, || unsafe {
+        asm!("csrr  {}, 0x14d", out(reg) _, options(nomem, nostack)); // 0x14d => stimecmp
+    });
+    ans != 2
+}
+
 // Tries to execute all instructions defined in clojure `f`.
 // If resulted in an exception, this function returns its exception id.
 //
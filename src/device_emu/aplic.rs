@@ -0,0 +1,407 @@
+//! Physical APLIC driven by hypervisor and per guest virtual APLIC.
+//!
+//! Guest APLIC accesses always trap. Guests must use MSI delivery mode, the
+//! sources they own are programmed into physical APLIC with the target
+//! translated to IMSIC guest interrupt file of the vcpu, so that interrupts
+//! are delivered into guest without hypervisor exit.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+use crate::device_emu::mmio::MmioDevice;
+use crate::{guest::page_table::GuestPageTable, hypervisor::HostVmm, page_table::{PageTable, PageTableSv39}};
+use crate::VmmResult;
+
+/// source 0 does not exist
+pub const APLIC_MAX_IRQS: usize = 1024;
+const APLIC_IRQ_WORDS: usize = APLIC_MAX_IRQS / 32;
+
+const APLIC_DOMAINCFG: usize = 0x0;
+const APLIC_SOURCECFG_BASE: usize = 0x0;
+const APLIC_SETIP_BASE: usize = 0x1c00;
+const APLIC_SETIPNUM: usize = 0x1cdc;
+const APLIC_CLRIP_BASE: usize = 0x1d00;
+const APLIC_CLRIPNUM: usize = 0x1ddc;
+const APLIC_SETIE_BASE: usize = 0x1e00;
+const APLIC_SETIENUM: usize = 0x1edc;
+const APLIC_CLRIE_BASE: usize = 0x1f00;
+const APLIC_CLRIENUM: usize = 0x1fdc;
+const APLIC_SETIPNUM_LE: usize = 0x2000;
+const APLIC_TARGET_BASE: usize = 0x3000;
+
+/// reads of `domaincfg` have top byte 0x80
+const DOMAINCFG_FIXED: u32 = 0x8000_0000;
+/// interrupt enable
+const DOMAINCFG_IE: u32 = 1 << 8;
+/// MSI delivery mode
+const DOMAINCFG_DM: u32 = 1 << 2;
+
+/// source mode of `sourcecfg`
+const SOURCECFG_SM_MASK: u32 = 0x7;
+const SOURCECFG_SM_INACTIVE: u32 = 0;
+
+const TARGET_HART_SHIFT: u32 = 18;
+const TARGET_GUEST_SHIFT: u32 = 12;
+const TARGET_GUEST_MASK: u32 = 0x3f;
+const TARGET_EIID_MASK: u32 = 0x7ff;
+
+fn target(hart: usize, guest: usize, eiid: u32) -> u32 {
+    (hart as u32) << TARGET_HART_SHIFT
+        | (guest as u32 & TARGET_GUEST_MASK) << TARGET_GUEST_SHIFT
+        | eiid & TARGET_EIID_MASK
+}
+
+/// physical APLIC
+pub struct AplicState {
+    pub base_addr: usize,
+    /// guest which receives each source
+    pub owner: Vec<Option<usize>>,
+}
+
+impl AplicState {
+    pub fn new(base_addr: usize) -> Self {
+        Self {
+            base_addr,
+            owner: vec![None; APLIC_MAX_IRQS],
+        }
+    }
+
+    fn reg(&self, offset: usize) -> *mut u32 {
+        (self.base_addr + offset) as *mut u32
+    }
+
+    fn write(&mut self, offset: usize, value: u32) {
+        unsafe { core::ptr::write_volatile(self.reg(offset), value) }
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile(self.reg(offset)) }
+    }
+
+    /// enable domain in MSI delivery mode
+    pub fn init(&mut self) {
+        self.write(APLIC_DOMAINCFG, DOMAINCFG_IE | DOMAINCFG_DM);
+    }
+
+    pub fn set_sourcecfg(&mut self, irq: usize, sourcecfg: u32) {
+        self.write(APLIC_SOURCECFG_BASE + 4 * irq, sourcecfg);
+    }
+
+    pub fn set_target(&mut self, irq: usize, hart: usize, guest: usize, eiid: u32) {
+        self.write(APLIC_TARGET_BASE + 4 * irq, target(hart, guest, eiid));
+    }
+
+    pub fn set_enable(&mut self, irq: usize, enable: bool) {
+        let offset = if enable { APLIC_SETIENUM } else { APLIC_CLRIENUM };
+        self.write(offset, irq as u32);
+    }
+
+    pub fn set_pending(&mut self, irq: usize, pending: bool) {
+        let offset = if pending { APLIC_SETIPNUM } else { APLIC_CLRIPNUM };
+        self.write(offset, irq as u32);
+    }
+
+    /// pending bits of sources `32 * word..32 * (word + 1)`
+    pub fn pending(&self, word: usize) -> u32 {
+        self.read(APLIC_SETIP_BASE + 4 * word)
+    }
+
+    /// turn source off, it no longer sends any MSI
+    pub fn deactivate(&mut self, irq: usize) {
+        self.set_enable(irq, false);
+        self.set_sourcecfg(irq, SOURCECFG_SM_INACTIVE);
+    }
+}
+
+/// registers of virtual APLIC which must be synchronized to physical APLIC
+pub enum AplicUpdate {
+    /// source mode, target or enable of source changed
+    Source(usize),
+    /// interrupt enable or delivery mode of domain changed
+    Domain,
+    /// guest sets or clears pending bit of source
+    Pending(usize, bool),
+}
+
+/// per guest virtual APLIC, a domain without children
+pub struct VirtAplic {
+    domaincfg: u32,
+    sourcecfg: Vec<u32>,
+    /// `hart index, guest index, EIID` seen by guest
+    target: Vec<u32>,
+    enable: Vec<u32>,
+}
+
+impl VirtAplic {
+    pub fn new() -> Self {
+        Self {
+            domaincfg: 0,
+            sourcecfg: vec![0; APLIC_MAX_IRQS],
+            target: vec![0; APLIC_MAX_IRQS],
+            enable: vec![0; APLIC_IRQ_WORDS],
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// domain delivers interrupts by MSI
+    pub fn is_active(&self) -> bool {
+        self.domaincfg & (DOMAINCFG_IE | DOMAINCFG_DM) == DOMAINCFG_IE | DOMAINCFG_DM
+    }
+
+    pub fn sourcecfg(&self, irq: usize) -> u32 {
+        self.sourcecfg[irq]
+    }
+
+    pub fn is_enabled(&self, irq: usize) -> bool {
+        self.enable[irq / 32] & 1 << (irq % 32) != 0
+    }
+
+    /// (hart index, EIID) of source
+    pub fn target(&self, irq: usize) -> (usize, u32) {
+        let target = self.target[irq];
+        ((target >> TARGET_HART_SHIFT) as usize, target & TARGET_EIID_MASK)
+    }
+
+    fn set_enable(&mut self, irq: usize, enable: bool) -> Option<AplicUpdate> {
+        if irq == 0 || irq >= APLIC_MAX_IRQS {
+            return None;
+        }
+        // enable bit of inactive source is read only zero
+        if enable && self.sourcecfg[irq] == SOURCECFG_SM_INACTIVE {
+            return None;
+        }
+        if enable {
+            self.enable[irq / 32] |= 1 << (irq % 32);
+        } else {
+            self.enable[irq / 32] &= !(1 << (irq % 32));
+        }
+        Some(AplicUpdate::Source(irq))
+    }
+
+    /// registers without side effect, pending bits are read from physical APLIC
+    pub fn read_reg(&self, offset: usize) -> u32 {
+        match offset {
+            APLIC_DOMAINCFG => DOMAINCFG_FIXED | self.domaincfg,
+            0x4..=0xffc => self.sourcecfg[offset / 4],
+            APLIC_SETIE_BASE..=0x1e7c => self.enable[(offset - APLIC_SETIE_BASE) / 4],
+            0x3004..=0x3ffc => self.target[(offset - APLIC_TARGET_BASE) / 4],
+            _ => 0,
+        }
+    }
+
+    /// Write register at `offset`, return what physical APLIC should follow.
+    /// Writes to `in_clrip` are split by caller into single sources.
+    pub fn write_reg(&mut self, offset: usize, value: u32) -> Option<AplicUpdate> {
+        match offset {
+            APLIC_DOMAINCFG => {
+                // big endian is not supported
+                self.domaincfg = value & (DOMAINCFG_IE | DOMAINCFG_DM);
+                Some(AplicUpdate::Domain)
+            }
+            0x4..=0xffc => {
+                let irq = offset / 4;
+                // guest domain has no child, delegation is not allowed
+                let sm = match value & SOURCECFG_SM_MASK {
+                    sm @ (1 | 4..=7) if value & (1 << 10) == 0 => sm,
+                    _ => SOURCECFG_SM_INACTIVE,
+                };
+                self.sourcecfg[irq] = sm;
+                if sm == SOURCECFG_SM_INACTIVE {
+                    self.enable[irq / 32] &= !(1 << (irq % 32));
+                    self.target[irq] = 0;
+                }
+                Some(AplicUpdate::Source(irq))
+            }
+            APLIC_SETIPNUM | APLIC_SETIPNUM_LE => Some(AplicUpdate::Pending(value as usize, true)),
+            APLIC_CLRIPNUM => Some(AplicUpdate::Pending(value as usize, false)),
+            APLIC_SETIENUM => self.set_enable(value as usize, true),
+            APLIC_CLRIENUM => self.set_enable(value as usize, false),
+            0x3004..=0x3ffc => {
+                let irq = (offset - APLIC_TARGET_BASE) / 4;
+                if self.sourcecfg[irq] == SOURCECFG_SM_INACTIVE {
+                    return None;
+                }
+                // guest has no guest interrupt files
+                let (hart, eiid) = ((value >> TARGET_HART_SHIFT) as usize, value & TARGET_EIID_MASK);
+                self.target[irq] = target(hart, 0, eiid);
+                Some(AplicUpdate::Source(irq))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl<G: GuestPageTable> MmioDevice<G> for VirtAplic {
+    fn read(
+        &mut self,
+        host_vmm: &mut HostVmm<PageTableSv39, G>,
+        guest_id: usize,
+        offset: usize,
+        width: usize,
+    ) -> VmmResult<usize> {
+        // all registers are 32 bit, other accesses read as zero
+        if width != 4 {
+            hwarning!("APLIC: {}-byte read at {:#x} ignored", width, offset);
+            return Ok(0);
+        }
+        let value = match offset {
+            APLIC_SETIP_BASE..=0x1c7c | APLIC_CLRIP_BASE..=0x1d7c => {
+                let word = (offset & 0xff) / 4;
+                host_vmm.guest_pending(guest_id, word)
+            }
+            _ => self.read_reg(offset),
+        };
+        Ok(value as usize)
+    }
+
+    fn write(
+        &mut self,
+        host_vmm: &mut HostVmm<PageTableSv39, G>,
+        guest_id: usize,
+        offset: usize,
+        width: usize,
+        value: usize,
+    ) -> VmmResult {
+        // and writes of other width are ignored
+        if width != 4 {
+            hwarning!("APLIC: {}-byte write at {:#x} ignored", width, offset);
+            return Ok(());
+        }
+        let value = value as u32;
+        let updates: Vec<AplicUpdate> = match offset {
+            // write 1 to set or clear pending or enable bits of a word
+            APLIC_SETIP_BASE..=0x1c7c | APLIC_CLRIP_BASE..=0x1d7c => {
+                let pending = offset < APLIC_CLRIP_BASE;
+                let word = (offset & 0xff) / 4;
+                (0..32)
+                    .filter(|bit| value & 1 << bit != 0)
+                    .map(|bit| AplicUpdate::Pending(32 * word + bit, pending))
+                    .collect()
+            }
+            APLIC_SETIE_BASE..=0x1e7c | APLIC_CLRIE_BASE..=0x1f7c => {
+                let enable = offset < APLIC_CLRIE_BASE;
+                let word = (offset & 0xff) / 4;
+                (0..32)
+                    .filter(|bit| value & 1 << bit != 0)
+                    .filter_map(|bit| self.set_enable(32 * word + bit, enable))
+                    .collect()
+            }
+            _ => self.write_reg(offset, value).into_iter().collect(),
+        };
+        for update in updates {
+            host_vmm.sync_physical_aplic(guest_id, self, update);
+        }
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    /// pending bits of sources owned by guest
+    fn guest_pending(&self, guest_id: usize, word: usize) -> u32 {
+        let Some(host_aplic) = self.host_aplic.as_ref() else {
+            return 0;
+        };
+        let owned = (0..32)
+            .filter(|bit| host_aplic.owner[32 * word + bit] == Some(guest_id))
+            .fold(0u32, |mask, bit| mask | 1 << bit);
+        host_aplic.pending(word) & owned
+    }
+
+    fn sync_physical_aplic(&mut self, guest_id: usize, vaplic: &VirtAplic, update: AplicUpdate) {
+        match update {
+            AplicUpdate::Source(irq) => self.sync_physical_source(guest_id, vaplic, irq),
+            AplicUpdate::Domain => {
+                for irq in 1..APLIC_MAX_IRQS {
+                    self.sync_physical_source(guest_id, vaplic, irq);
+                }
+            }
+            AplicUpdate::Pending(irq, pending) => {
+                let Some(host_aplic) = self.host_aplic.as_mut() else {
+                    return;
+                };
+                if irq < APLIC_MAX_IRQS && host_aplic.owner[irq] == Some(guest_id) {
+                    host_aplic.set_pending(irq, pending);
+                }
+            }
+        }
+    }
+
+    /// Program source owned by guest into physical APLIC, MSI goes to
+    /// guest interrupt file of the target vcpu on current hart.
+    fn sync_physical_source(&mut self, guest_id: usize, vaplic: &VirtAplic, irq: usize) {
+        let hart_id = self.hart_id;
+        let (Some(host_aplic), Some(guest)) = (self.host_aplic.as_mut(), self.guests[guest_id].as_ref()) else {
+            return;
+        };
+        if irq == 0 || irq >= APLIC_MAX_IRQS || host_aplic.owner[irq] != Some(guest_id) {
+            return;
+        }
+        let sourcecfg = vaplic.sourcecfg(irq);
+        let (hart, eiid) = vaplic.target(irq);
+        let vgein = guest.vcpus.iter().find(|vcpu| vcpu.hart == hart).and_then(|vcpu| vcpu.vgein);
+        match vgein {
+            Some(vgein) if vaplic.is_active() && sourcecfg != SOURCECFG_SM_INACTIVE => {
+                host_aplic.set_sourcecfg(irq, sourcecfg);
+                host_aplic.set_target(irq, hart_id, vgein, eiid);
+                host_aplic.set_enable(irq, vaplic.is_enabled(irq));
+            }
+            None if sourcecfg != SOURCECFG_SM_INACTIVE => {
+                hwarning!("guest {} irq {}: hart {} has no guest interrupt file", guest_id, irq, hart);
+                host_aplic.deactivate(irq);
+            }
+            _ => host_aplic.deactivate(irq),
+        }
+    }
+
+    /// route physical source `irq` to guest, return false if it is owned by another guest
+    pub fn assign_aplic_irq(&mut self, irq: usize, guest_id: usize) -> bool {
+        let Some(host_aplic) = self.host_aplic.as_mut() else {
+            return false;
+        };
+        if irq == 0 || irq >= APLIC_MAX_IRQS {
+            hwarning!("invalid irq {} for guest {}", irq, guest_id);
+            return false;
+        }
+        match host_aplic.owner[irq] {
+            Some(owner) if owner != guest_id => {
+                hwarning!("irq {} is owned by guest {}, guest {} can not use it", irq, owner, guest_id);
+                false
+            }
+            _ => {
+                hdebug!("route irq {} to guest {}", irq, guest_id);
+                host_aplic.owner[irq] = Some(guest_id);
+                true
+            }
+        }
+    }
+
+    /// Turn off physical sources of guest and reset its virtual APLIC, the
+    /// sources are given back too if `release`.
+    pub fn reset_guest_aplic(&mut self, guest_id: usize, release: bool) {
+        let Some(host_aplic) = self.host_aplic.as_mut() else {
+            return;
+        };
+        for irq in 1..APLIC_MAX_IRQS {
+            if host_aplic.owner[irq] == Some(guest_id) {
+                host_aplic.deactivate(irq);
+                if release {
+                    host_aplic.owner[irq] = None;
+                }
+            }
+        }
+        if let Some(vaplic) = self.guests[guest_id].as_mut().and_then(|guest| guest.mmio.device_mut::<VirtAplic>()) {
+            vaplic.reset();
+        }
+    }
+}
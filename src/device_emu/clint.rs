@@ -0,0 +1,141 @@
+//! Virtual CLINT of guest.
+//!
+//! Physical CLINT belongs to M-mode and is never mapped into guests. `mtime`
+//! reads return guest time, `mtimecmp` of each vcpu is backed by the timer
+//! queue and `msip` raises virtual software interrupt of the vcpu.
+
+use riscv::register::time;
+
+use core::any::Any;
+use crate::device_emu::mmio::MmioDevice;
+use crate::{guest::page_table::GuestPageTable, hypervisor::HostVmm, page_table::{PageTable, PageTableSv39}};
+use crate::VmmResult;
+
+const CLINT_MSIP_BASE: usize = 0x0;
+const CLINT_MTIMECMP_BASE: usize = 0x4000;
+const CLINT_MTIME: usize = 0xbff8;
+
+/// register of virtual CLINT at offset
+#[derive(Clone, Copy)]
+enum ClintReg {
+    /// `msip` of vcpu
+    Msip(usize),
+    /// `mtimecmp` of vcpu, upper half if true
+    Mtimecmp(usize, bool),
+    /// `mtime`, upper half if true
+    Mtime(bool),
+}
+
+fn clint_reg(offset: usize) -> Option<ClintReg> {
+    match offset {
+        CLINT_MSIP_BASE..=0x3ffc => Some(ClintReg::Msip(offset / 4)),
+        CLINT_MTIMECMP_BASE..=0xbff4 => {
+            let offset = offset - CLINT_MTIMECMP_BASE;
+            Some(ClintReg::Mtimecmp(offset / 8, offset % 8 == 4))
+        }
+        CLINT_MTIME => Some(ClintReg::Mtime(false)),
+        0xbffc => Some(ClintReg::Mtime(true)),
+        _ => None,
+    }
+}
+
+/// Virtual CLINT of guest. Its registers are kept by vcpus and timer queue
+/// of hypervisor, so the device itself holds nothing.
+pub struct VirtClint;
+
+impl<G: GuestPageTable> MmioDevice<G> for VirtClint {
+    fn read(
+        &mut self,
+        host_vmm: &mut HostVmm<PageTableSv39, G>,
+        guest_id: usize,
+        offset: usize,
+        width: usize,
+    ) -> VmmResult<usize> {
+        // holes and accesses of other width read as zero
+        let Some(reg) = clint_reg(offset).filter(|_| width == 4 || width == 8) else {
+            hwarning!("CLINT: {}-byte read at {:#x} ignored", width, offset);
+            return Ok(0);
+        };
+        Ok(host_vmm.read_clint(guest_id, reg))
+    }
+
+    fn write(
+        &mut self,
+        host_vmm: &mut HostVmm<PageTableSv39, G>,
+        guest_id: usize,
+        offset: usize,
+        width: usize,
+        value: usize,
+    ) -> VmmResult {
+        // and writes to them are ignored
+        let Some(reg) = clint_reg(offset).filter(|_| width == 4 || width == 8) else {
+            hwarning!("CLINT: {}-byte write at {:#x} ignored", width, offset);
+            return Ok(());
+        };
+        host_vmm.write_clint(guest_id, reg, value, width == 4);
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    fn read_clint(&self, guest_id: usize, reg: ClintReg) -> usize {
+        let guest = self.guests[guest_id].as_ref().unwrap();
+        let value = match reg {
+            ClintReg::Msip(hart) => match guest.vcpus.iter().position(|vcpu| vcpu.hart == hart) {
+                Some(vcpu_id) => guest.vcpus[vcpu_id].ipi_pending(vcpu_id == guest.vcpu_id) as usize,
+                None => 0,
+            },
+            ClintReg::Mtimecmp(hart, _) => guest
+                .vcpus
+                .iter()
+                .find(|vcpu| vcpu.hart == hart)
+                .map_or(0, |vcpu| vcpu.mtimecmp),
+            ClintReg::Mtime(_) => time::read().wrapping_add(guest.time_delta),
+        };
+        match reg {
+            ClintReg::Mtimecmp(_, true) | ClintReg::Mtime(true) => value >> 32,
+            _ => value,
+        }
+    }
+
+    /// `word` if written by 32 bit store
+    fn write_clint(&mut self, guest_id: usize, reg: ClintReg, value: usize, word: bool) {
+        let guest = self.guests[guest_id].as_mut().unwrap();
+        match reg {
+            ClintReg::Msip(hart) => {
+                let Some(vcpu_id) = guest.vcpus.iter().position(|vcpu| vcpu.hart == hart) else {
+                    return;
+                };
+                if value & 1 != 0 {
+                    guest.send_ipi(1 << hart);
+                } else {
+                    let running = vcpu_id == guest.vcpu_id;
+                    guest.vcpus[vcpu_id].clear_ipi(running);
+                }
+            }
+            ClintReg::Mtimecmp(hart, high) => {
+                let Some(vcpu_id) = guest.vcpus.iter().position(|vcpu| vcpu.hart == hart) else {
+                    return;
+                };
+                let vcpu = &mut guest.vcpus[vcpu_id];
+                vcpu.mtimecmp = match (word, high) {
+                    (false, _) => value,
+                    (true, false) => vcpu.mtimecmp & !0xffff_ffff | value,
+                    (true, true) => vcpu.mtimecmp & 0xffff_ffff | value << 32,
+                };
+                let deadline = guest.guest_time_to_host(guest.vcpus[vcpu_id].mtimecmp);
+                self.set_guest_vcpu_timer(guest_id, vcpu_id, deadline);
+            }
+            // guest time base is kept by hypervisor
+            ClintReg::Mtime(_) => hwarning!("guest {} writes mtime", guest_id),
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! IMSIC guest interrupt files assigned to vcpus.
+//!
+//! Each vcpu gets a guest interrupt file of current hart, which is mapped at
+//! supervisor level file of the vcpu in guest physical address space and
+//! selected by `hstatus.VGEIN` when the vcpu runs. Files of vcpus not running
+//! raise supervisor guest external interrupt through `hgeie` to wake them up.
+
+use crate::constants::PAGE_SIZE;
+use crate::constants::csr::{hgeie, hgeip};
+use crate::guest::{page_table::GuestPageTable, Guest, VCPU_EVENT_GEI};
+use crate::hypervisor::HostVmm;
+use crate::mm::{MapArea, MapPermission, MapType, MemorySet};
+use crate::page_table::PageTable;
+
+/// guest interrupt files implemented by hart, bit 0 is always zero
+pub fn probe_guest_files() -> usize {
+    unsafe {
+        hgeie::write(usize::MAX);
+        let files = hgeie::read();
+        hgeie::write(0);
+        files
+    }
+}
+
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    /// Assign a guest interrupt file to each vcpu of guest and map it,
+    /// vcpus without file can not use IMSIC.
+    pub fn attach_imsic(&mut self, guest: &mut Guest<G>) {
+        let (Some(host_imsic), Some(guest_imsic)) = (
+            self.host_machine.imsic.clone(),
+            guest.guest_machine.imsic.clone(),
+        ) else {
+            return;
+        };
+        let host_stride = PAGE_SIZE << self.host_machine.imsic_guest_bits;
+        let guest_stride = PAGE_SIZE << guest.guest_machine.imsic_guest_bits;
+        for vcpu in guest.vcpus.iter_mut() {
+            let free = self.imsic_files & !self.imsic_files_used;
+            if free == 0 {
+                hwarning!("guest {} vcpu {}: no guest interrupt file left", guest.guest_id, vcpu.hart);
+                continue;
+            }
+            let vgein = free.trailing_zeros() as usize;
+            self.imsic_files_used |= 1 << vgein;
+            vcpu.vgein = Some(vgein);
+            let host_pa = host_imsic.base_address + self.hart_id * host_stride + vgein * PAGE_SIZE;
+            let guest_pa = guest_imsic.base_address + vcpu.hart * guest_stride;
+            hdebug!(
+                "guest {} vcpu {}: guest interrupt file {} at {:#x} -> {:#x}",
+                guest.guest_id, vcpu.hart, vgein, guest_pa, host_pa
+            );
+            guest.gpm.push(
+                MapArea::new(
+                    guest_pa.into(),
+                    (guest_pa + PAGE_SIZE).into(),
+                    Some(host_pa.into()),
+                    Some((host_pa + PAGE_SIZE).into()),
+                    MapType::Linear,
+                    MapPermission::R | MapPermission::W | MapPermission::U,
+                ),
+                None,
+            );
+        }
+    }
+
+    /// give back guest interrupt files of guest
+    pub fn detach_imsic(&mut self, guest_id: usize) {
+        let Some(guest) = self.guests[guest_id].as_mut() else {
+            return;
+        };
+        for vcpu in guest.vcpus.iter_mut() {
+            if let Some(vgein) = vcpu.vgein.take() {
+                self.imsic_files_used &= !(1 << vgein);
+                self.gei_masked &= !(1 << vgein);
+            }
+        }
+    }
+
+    /// Enable guest external interrupts of all vcpus except the running one,
+    /// whose file is delivered by `VGEIN` directly. Running vcpu parked by
+    /// `wfi` needs them to be waken up too.
+    pub fn update_hgeie(&mut self) {
+        if self.imsic_files_used == 0 {
+            return;
+        }
+        let running = self.guests[self.guest_id]
+            .as_ref()
+            .map(|guest| &guest.vcpus[guest.vcpu_id])
+            .filter(|vcpu| !vcpu.wfi)
+            .and_then(|vcpu| vcpu.vgein)
+            .map_or(0, |vgein| 1 << vgein);
+        self.gei_masked &= !running;
+        unsafe { hgeie::write(self.imsic_files_used & !running & !self.gei_masked) };
+    }
+
+    /// Wake up vcpus whose guest interrupt file has pending interrupt. The
+    /// file keeps pending until vcpu runs, so it is masked until then.
+    pub fn handle_sgei(&mut self) {
+        let pending = hgeip::read() & hgeie::read();
+        self.gei_masked |= pending;
+        unsafe { hgeie::write(hgeie::read() & !pending) };
+        for guest in self.guests.iter_mut().flatten() {
+            for vcpu in guest.vcpus.iter_mut() {
+                if vcpu.vgein.map_or(false, |vgein| pending & 1 << vgein != 0) {
+                    vcpu.push_event(VCPU_EVENT_GEI);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,247 @@
+//! Decoder of guest loads and stores trapped on emulated MMIO regions and
+//! registry of devices emulating them.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::any::Any;
+use crate::guest::vmexit::TrapContext;
+use crate::{guest::page_table::GuestPageTable, hypervisor::HostVmm, page_table::PageTableSv39};
+use crate::{VmmError, VmmResult};
+
+const OPCODE_LOAD: u32 = 0b000_0011;
+const OPCODE_STORE: u32 = 0b010_0011;
+
+/// a load or store of guest to MMIO
+#[derive(Debug, Clone, Copy)]
+pub struct MmioAccess {
+    /// bytes accessed, 1, 2, 4 or 8
+    pub width: usize,
+    /// load is sign extended
+    pub signed: bool,
+    /// destination register of load or source register of store
+    pub reg: usize,
+    pub is_store: bool,
+    /// length of trapped instruction, `sepc` is advanced by it
+    pub len: usize,
+}
+
+impl MmioAccess {
+    /// Decode `htinst`, which holds transformed instruction. Its bit 1 is
+    /// clear if the trapped instruction is compressed.
+    pub fn from_htinst(htinst: usize) -> Option<Self> {
+        let len = if htinst & 0b10 == 0 { 2 } else { 4 };
+        Self::decode_32(htinst as u32 | 0b10, len)
+    }
+
+    /// decode raw instruction fetched from guest memory
+    pub fn from_raw(inst: u32) -> Option<Self> {
+        if inst & 0b11 == 0b11 {
+            Self::decode_32(inst, 4)
+        } else {
+            Self::decode_16(inst as u16)
+        }
+    }
+
+    fn decode_32(inst: u32, len: usize) -> Option<Self> {
+        let funct3 = (inst >> 12) & 0x7;
+        match inst & 0x7f {
+            OPCODE_LOAD => {
+                let (width, signed) = match funct3 {
+                    0 => (1, true),  // lb
+                    1 => (2, true),  // lh
+                    2 => (4, true),  // lw
+                    3 => (8, true),  // ld
+                    4 => (1, false), // lbu
+                    5 => (2, false), // lhu
+                    6 => (4, false), // lwu
+                    _ => return None,
+                };
+                let reg = ((inst >> 7) & 0x1f) as usize;
+                Some(Self { width, signed, reg, is_store: false, len })
+            }
+            OPCODE_STORE if funct3 <= 3 => {
+                // sb, sh, sw, sd
+                let reg = ((inst >> 20) & 0x1f) as usize;
+                Some(Self { width: 1 << funct3, signed: false, reg, is_store: true, len })
+            }
+            _ => None,
+        }
+    }
+
+    fn decode_16(inst: u16) -> Option<Self> {
+        let quadrant = inst & 0b11;
+        let funct3 = inst >> 13;
+        // rd' or rs2' of quadrant 0
+        let reg_c = ((inst >> 2) & 0x7) as usize + 8;
+        let rd = ((inst >> 7) & 0x1f) as usize;
+        let rs2 = ((inst >> 2) & 0x1f) as usize;
+        let (width, is_store, reg) = match (quadrant, funct3) {
+            (0b00, 0b010) => (4, false, reg_c), // c.lw
+            (0b00, 0b011) => (8, false, reg_c), // c.ld
+            (0b00, 0b110) => (4, true, reg_c),  // c.sw
+            (0b00, 0b111) => (8, true, reg_c),  // c.sd
+            (0b10, 0b010) => (4, false, rd),    // c.lwsp
+            (0b10, 0b011) => (8, false, rd),    // c.ldsp
+            (0b10, 0b110) => (4, true, rs2),    // c.swsp
+            (0b10, 0b111) => (8, true, rs2),    // c.sdsp
+            _ => return None,
+        };
+        Some(Self { width, signed: !is_store, reg, is_store, len: 2 })
+    }
+
+    fn mask(&self) -> usize {
+        if self.width == 8 {
+            usize::MAX
+        } else {
+            (1 << (self.width * 8)) - 1
+        }
+    }
+
+    /// value stored by guest, truncated to access width
+    pub fn store_value(&self, ctx: &TrapContext) -> usize {
+        ctx.x[self.reg] & self.mask()
+    }
+
+    /// write value read from device into destination register, extended as the load does
+    pub fn complete_load(&self, ctx: &mut TrapContext, value: usize) {
+        if self.reg == 0 {
+            return;
+        }
+        let shift = 64 - self.width * 8;
+        ctx.x[self.reg] = if self.signed {
+            ((value << shift) as isize >> shift) as usize
+        } else {
+            value & self.mask()
+        };
+    }
+}
+
+/// A device emulated for guests on MMIO. Devices are registered into
+/// `MmioRegistry` of each guest at their guest physical range, trapped
+/// accesses in the range are emulated by the device. The device is taken
+/// out of the registry while it works on `host_vmm`.
+pub trait MmioDevice<G: GuestPageTable>: Any + Send + Sync {
+    /// read `width` bytes at `offset` of device for guest `guest_id`
+    fn read(
+        &mut self,
+        host_vmm: &mut HostVmm<PageTableSv39, G>,
+        guest_id: usize,
+        offset: usize,
+        width: usize,
+    ) -> VmmResult<usize>;
+
+    /// write low `width` bytes of `value` at `offset` of device for guest `guest_id`
+    fn write(
+        &mut self,
+        host_vmm: &mut HostVmm<PageTableSv39, G>,
+        guest_id: usize,
+        offset: usize,
+        width: usize,
+        value: usize,
+    ) -> VmmResult;
+
+    /// Called after each access of guest, so that hypervisor and physical
+    /// devices backing the device follow what guest did. Does nothing by default.
+    fn after_access(&mut self, _host_vmm: &mut HostVmm<PageTableSv39, G>, _guest_id: usize) {}
+
+    fn as_any(&self) -> &dyn Any;
+
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+struct MmioRegion<G: GuestPageTable> {
+    base: usize,
+    size: usize,
+    device: Box<dyn MmioDevice<G>>,
+}
+
+impl<G: GuestPageTable> MmioRegion<G> {
+    fn contains(&self, guest_pa: usize) -> bool {
+        guest_pa >= self.base && guest_pa - self.base < self.size
+    }
+
+    fn overlaps(&self, base: usize, size: usize) -> bool {
+        base < self.base + self.size && self.base < base + size
+    }
+}
+
+/// emulated MMIO regions of a guest
+pub struct MmioRegistry<G: GuestPageTable> {
+    regions: Vec<MmioRegion<G>>,
+}
+
+impl<G: GuestPageTable> MmioRegistry<G> {
+    pub fn new() -> Self {
+        Self { regions: Vec::new() }
+    }
+
+    /// register device at `[base, base + size)`, replacing devices overlapping it
+    pub fn register(&mut self, base: usize, size: usize, device: Box<dyn MmioDevice<G>>) {
+        self.regions.retain(|region| !region.overlaps(base, size));
+        self.regions.push(MmioRegion { base, size, device });
+    }
+
+    /// whether `guest_pa` is in an emulated region
+    pub fn contains(&self, guest_pa: usize) -> bool {
+        self.regions.iter().any(|region| region.contains(guest_pa))
+    }
+
+    /// registered device of type `T`
+    pub fn device<T: MmioDevice<G>>(&self) -> Option<&T> {
+        self.regions.iter().find_map(|region| region.device.as_any().downcast_ref::<T>())
+    }
+
+    pub fn device_mut<T: MmioDevice<G>>(&mut self) -> Option<&mut T> {
+        self.regions.iter_mut().find_map(|region| region.device.as_any_mut().downcast_mut::<T>())
+    }
+
+    /// remove region covering `guest_pa`, it is put back by `put_back`
+    fn take(&mut self, guest_pa: usize) -> Option<MmioRegion<G>> {
+        let index = self.regions.iter().position(|region| region.contains(guest_pa))?;
+        Some(self.regions.swap_remove(index))
+    }
+
+    fn put_back(&mut self, region: MmioRegion<G>) {
+        self.regions.push(region);
+    }
+}
+
+impl<G: GuestPageTable> HostVmm<PageTableSv39, G> {
+    /// whether `guest_pa` is in an emulated region of current guest
+    pub fn is_mmio_access(&self, guest_pa: usize) -> bool {
+        self.guests[self.guest_id]
+            .as_ref()
+            .map_or(false, |guest| guest.mmio.contains(guest_pa))
+    }
+
+    /// Emulate access of current guest on the device registered at
+    /// `guest_pa`, then let the device apply it on hypervisor.
+    pub fn handle_mmio_access(
+        &mut self,
+        ctx: &mut TrapContext,
+        guest_pa: usize,
+        access: MmioAccess,
+    ) -> VmmResult {
+        let guest_id = self.guest_id;
+        let guest = self.guests[guest_id].as_mut().ok_or(VmmError::DeviceNotFound)?;
+        let mut region = guest.mmio.take(guest_pa).ok_or(VmmError::DeviceNotFound)?;
+        let offset = guest_pa - region.base;
+        let result = if access.is_store {
+            let value = access.store_value(ctx);
+            region.device.write(self, guest_id, offset, access.width, value)
+        } else {
+            region
+                .device
+                .read(self, guest_id, offset, access.width)
+                .map(|value| access.complete_load(ctx, value))
+        };
+        if result.is_ok() {
+            region.device.after_access(self, guest_id);
+        }
+        // guest is gone if it powered itself off through the device
+        if let Some(guest) = self.guests[guest_id].as_mut() {
+            guest.mmio.put_back(region);
+        }
+        result
+    }
+}
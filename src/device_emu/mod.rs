@@ -1 +1,6 @@
-pub mod plic;
\ No newline at end of file
+pub mod aplic;
+pub mod clint;
+pub mod imsic;
+pub mod mmio;
+pub mod plic;
+pub mod test_finisher;
@@ -1,12 +1,18 @@
-use riscv::register::hvip;
-use riscv_decode::Instruction;
+//! Physical PLIC driven by hypervisor and per guest virtual PLIC.
+//!
+//! Virtual PLIC is registered as MMIO device of guest, so accesses of guest
+//! always trap. Each physical source is owned by at most one guest. Hypervisor claims physical interrupts in its own context and
+//! makes them pending in virtual PLIC of the owner, the source is completed
+//! when the owner completes it.
 
-use crate::guest::vmexit::TrapContext;
-use crate::{
-    constants::MAX_CONTEXTS, guest::page_table::GuestPageTable, hypervisor::HostVmm,
-    page_table::PageTable,
-};
-use crate::{VmmError, VmmResult};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+use crate::constants::csr::hvip;
+use crate::device_emu::mmio::MmioDevice;
+use crate::guest::VCpu;
+use crate::{guest::page_table::GuestPageTable, hypervisor::HostVmm, page_table::{PageTable, PageTableSv39}};
+use crate::VmmResult;
 
 pub const PLIC_OFFSET: &[(usize, usize)] = &[
     (0x0, 0x1000),                   // Interrupt priority
@@ -15,99 +21,427 @@ pub const PLIC_OFFSET: &[(usize, usize)] = &[
     (0x200000, 0x600000 - 0x200000), // threshold/claim/complete
 ];
 
+/// source 0 does not exist
+pub const PLIC_MAX_IRQS: usize = 1024;
+const PLIC_IRQ_WORDS: usize = PLIC_MAX_IRQS / 32;
+
+const PLIC_PRIORITY_BASE: usize = 0x0;
+const PLIC_PENDING_BASE: usize = 0x1000;
+const PLIC_ENABLE_BASE: usize = 0x2000;
+const PLIC_ENABLE_STRIDE: usize = 0x80;
+const PLIC_CONTEXT_BASE: usize = 0x200000;
+const PLIC_CONTEXT_STRIDE: usize = 0x1000;
+const PLIC_CONTEXT_THRESHOLD: usize = 0x0;
+const PLIC_CONTEXT_CLAIM: usize = 0x4;
+
+/// S-mode context of `hart`, context `2 * hart` is M-mode
+pub fn plic_s_context(hart: usize) -> usize {
+    2 * hart + 1
+}
+
+/// physical PLIC
 pub struct PlicState {
     pub base_addr: usize,
-    pub claim_complete: [u32; MAX_CONTEXTS],
+    /// guest which receives each source
+    pub owner: Vec<Option<usize>>,
 }
 
 impl PlicState {
     pub fn new(base_addr: usize) -> Self {
         Self {
             base_addr,
-            claim_complete: [0u32; MAX_CONTEXTS],
+            owner: vec![None; PLIC_MAX_IRQS],
         }
     }
+
+    fn reg(&self, offset: usize) -> *mut u32 {
+        (self.base_addr + offset) as *mut u32
+    }
+
+    pub fn set_priority(&mut self, irq: usize, priority: u32) {
+        unsafe { core::ptr::write_volatile(self.reg(PLIC_PRIORITY_BASE + 4 * irq), priority) }
+    }
+
+    pub fn set_enable(&mut self, context: usize, irq: usize, enable: bool) {
+        let reg = self.reg(PLIC_ENABLE_BASE + PLIC_ENABLE_STRIDE * context + 4 * (irq / 32));
+        unsafe {
+            let value = core::ptr::read_volatile(reg);
+            let value = if enable { value | 1 << (irq % 32) } else { value & !(1 << (irq % 32)) };
+            core::ptr::write_volatile(reg, value);
+        }
+    }
+
+    pub fn set_threshold(&mut self, context: usize, threshold: u32) {
+        let reg = self.reg(PLIC_CONTEXT_BASE + PLIC_CONTEXT_STRIDE * context + PLIC_CONTEXT_THRESHOLD);
+        unsafe { core::ptr::write_volatile(reg, threshold) }
+    }
+
+    pub fn claim(&mut self, context: usize) -> usize {
+        let reg = self.reg(PLIC_CONTEXT_BASE + PLIC_CONTEXT_STRIDE * context + PLIC_CONTEXT_CLAIM);
+        unsafe { core::ptr::read_volatile(reg) as usize }
+    }
+
+    pub fn complete(&mut self, context: usize, irq: usize) {
+        let reg = self.reg(PLIC_CONTEXT_BASE + PLIC_CONTEXT_STRIDE * context + PLIC_CONTEXT_CLAIM);
+        unsafe { core::ptr::write_volatile(reg, irq as u32) }
+    }
 }
 
-impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
-    pub fn handle_plic_access(
+/// per guest virtual PLIC
+pub struct VirtPlic {
+    nr_contexts: usize,
+    priority: Vec<u32>,
+    pending: Vec<u32>,
+    /// sources claimed and not completed yet
+    claimed: Vec<u32>,
+    enable: Vec<u32>,
+    threshold: Vec<u32>,
+    /// sources completed by guest, not completed in physical PLIC yet
+    completed: Vec<usize>,
+    /// enable words written by guest, bit `i` for sources `32 * i..32 * (i + 1)`
+    enable_dirty: u32,
+}
+
+impl VirtPlic {
+    /// `nr_contexts` contexts, M-mode and S-mode context for each vcpu
+    pub fn new(nr_contexts: usize) -> Self {
+        Self {
+            nr_contexts,
+            priority: vec![0; PLIC_MAX_IRQS],
+            pending: vec![0; PLIC_IRQ_WORDS],
+            claimed: vec![0; PLIC_IRQ_WORDS],
+            enable: vec![0; nr_contexts * PLIC_IRQ_WORDS],
+            threshold: vec![0; nr_contexts],
+            completed: Vec::new(),
+            enable_dirty: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new(self.nr_contexts);
+    }
+
+    fn test(bits: &[u32], irq: usize) -> bool {
+        bits[irq / 32] & 1 << (irq % 32) != 0
+    }
+
+    fn assign(bits: &mut [u32], irq: usize, value: bool) {
+        if value {
+            bits[irq / 32] |= 1 << (irq % 32);
+        } else {
+            bits[irq / 32] &= !(1 << (irq % 32));
+        }
+    }
+
+    pub fn set_pending(&mut self, irq: usize, pending: bool) {
+        if irq != 0 && irq < PLIC_MAX_IRQS {
+            Self::assign(&mut self.pending, irq, pending);
+        }
+    }
+
+    /// sources pending or claimed, which are still held by guest
+    pub fn active_irqs(&self) -> impl Iterator<Item = usize> + '_ {
+        (1..PLIC_MAX_IRQS).filter(|&irq| Self::test(&self.pending, irq) || Self::test(&self.claimed, irq))
+    }
+
+    /// whether `irq` is enabled in any context
+    pub fn is_enabled(&self, irq: usize) -> bool {
+        (0..self.nr_contexts).any(|context| Self::test(&self.enable[context * PLIC_IRQ_WORDS..], irq))
+    }
+
+    /// pending and enabled source with highest priority above threshold of `context`
+    pub fn best_irq(&self, context: usize) -> Option<usize> {
+        if context >= self.nr_contexts {
+            return None;
+        }
+        let enable = &self.enable[context * PLIC_IRQ_WORDS..(context + 1) * PLIC_IRQ_WORDS];
+        let mut best: Option<usize> = None;
+        for irq in 1..PLIC_MAX_IRQS {
+            if !Self::test(&self.pending, irq) || !Self::test(enable, irq) {
+                continue;
+            }
+            let priority = self.priority[irq];
+            if priority <= self.threshold[context] {
+                continue;
+            }
+            // lower source id wins when priorities are equal
+            if best.map_or(true, |best| priority > self.priority[best]) {
+                best = Some(irq);
+            }
+        }
+        best
+    }
+
+    fn claim(&mut self, context: usize) -> usize {
+        match self.best_irq(context) {
+            Some(irq) => {
+                Self::assign(&mut self.pending, irq, false);
+                Self::assign(&mut self.claimed, irq, true);
+                irq
+            }
+            None => 0,
+        }
+    }
+
+    /// return whether `irq` was claimed
+    fn complete(&mut self, irq: usize) -> bool {
+        if irq == 0 || irq >= PLIC_MAX_IRQS || !Self::test(&self.claimed, irq) {
+            return false;
+        }
+        Self::assign(&mut self.claimed, irq, false);
+        true
+    }
+
+    fn read_reg(&mut self, offset: usize) -> u32 {
+        match offset {
+            PLIC_PRIORITY_BASE..=0xffc => self.priority[offset / 4],
+            PLIC_PENDING_BASE..=0x107f => self.pending[(offset - PLIC_PENDING_BASE) / 4],
+            PLIC_ENABLE_BASE..=0x1f_fffc => {
+                let context = (offset - PLIC_ENABLE_BASE) / PLIC_ENABLE_STRIDE;
+                let word = (offset - PLIC_ENABLE_BASE) % PLIC_ENABLE_STRIDE / 4;
+                if context < self.nr_contexts {
+                    self.enable[context * PLIC_IRQ_WORDS + word]
+                } else {
+                    0
+                }
+            }
+            _ if offset >= PLIC_CONTEXT_BASE => {
+                let context = (offset - PLIC_CONTEXT_BASE) / PLIC_CONTEXT_STRIDE;
+                if context >= self.nr_contexts {
+                    return 0;
+                }
+                match (offset - PLIC_CONTEXT_BASE) % PLIC_CONTEXT_STRIDE {
+                    PLIC_CONTEXT_THRESHOLD => self.threshold[context],
+                    PLIC_CONTEXT_CLAIM => self.claim(context) as u32,
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&mut self, offset: usize, value: u32) {
+        match offset {
+            PLIC_PRIORITY_BASE..=0xffc => {
+                if offset != 0 {
+                    // 7 priority levels
+                    self.priority[offset / 4] = value & 0x7;
+                }
+            }
+            PLIC_ENABLE_BASE..=0x1f_fffc => {
+                let context = (offset - PLIC_ENABLE_BASE) / PLIC_ENABLE_STRIDE;
+                let word = (offset - PLIC_ENABLE_BASE) % PLIC_ENABLE_STRIDE / 4;
+                if context < self.nr_contexts {
+                    // source 0 does not exist
+                    let value = if word == 0 { value & !1 } else { value };
+                    self.enable[context * PLIC_IRQ_WORDS + word] = value;
+                    self.enable_dirty |= 1 << word;
+                }
+            }
+            _ if offset >= PLIC_CONTEXT_BASE => {
+                let context = (offset - PLIC_CONTEXT_BASE) / PLIC_CONTEXT_STRIDE;
+                if context >= self.nr_contexts {
+                    return;
+                }
+                match (offset - PLIC_CONTEXT_BASE) % PLIC_CONTEXT_STRIDE {
+                    PLIC_CONTEXT_THRESHOLD => self.threshold[context] = value & 0x7,
+                    PLIC_CONTEXT_CLAIM if self.complete(value as usize) => self.completed.push(value as usize),
+                    _ => {}
+                }
+            }
+            // pending bits are read only
+            _ => {}
+        }
+    }
+}
+
+impl<G: GuestPageTable> MmioDevice<G> for VirtPlic {
+    fn read(
         &mut self,
-        ctx: &mut TrapContext,
-        guest_pa: usize,
-        instrution: Instruction,
+        _host_vmm: &mut HostVmm<PageTableSv39, G>,
+        _guest_id: usize,
+        offset: usize,
+        width: usize,
+    ) -> VmmResult<usize> {
+        // all registers are 32 bit, other accesses read as zero
+        if width != 4 {
+            hwarning!("PLIC: {}-byte read at {:#x} ignored", width, offset);
+            return Ok(0);
+        }
+        Ok(self.read_reg(offset) as usize)
+    }
+
+    fn write(
+        &mut self,
+        _host_vmm: &mut HostVmm<PageTableSv39, G>,
+        _guest_id: usize,
+        offset: usize,
+        width: usize,
+        value: usize,
     ) -> VmmResult {
-        let host_plic = self.host_plic.as_mut().unwrap();
-        let offset = guest_pa.wrapping_sub(host_plic.base_addr);
-        // threshold/claim/complete
-        if offset >= 0x200000 && offset < 0x200000 + 0x1000 * MAX_CONTEXTS {
-            let hart = (offset - 0x200000) / 0x1000;
-            let index = ((offset - 0x200000) & 0xfff) >> 2;
-            if index == 0 {
-                // threshold
-                match instrution {
-                    Instruction::Sw(i) => {
-                        // guest write threshold register to plic core
-                        let value = ctx.x[i.rs2() as usize] as u32;
-                        // hdebug!("PLIC write addr@{:#x} -> {:#x}", guest_pa, value);
-                        // todo: guest pa -> host pa
-                        // htracking!(
-                        //     "write PLIC threshold reg, addr: {:#x}, value: {:#x}",
-                        //     guest_pa,
-                        //     value
-                        // );
-                        unsafe {
-                            core::ptr::write_volatile(guest_pa as *mut u32, value);
-                        }
-                    }
-                    _ => return Err(VmmError::UnexpectedInst),
+        // and writes of other width are ignored
+        if width != 4 {
+            hwarning!("PLIC: {}-byte write at {:#x} ignored", width, offset);
+            return Ok(());
+        }
+        self.write_reg(offset, value as u32);
+        Ok(())
+    }
+
+    /// Apply accesses of guest on physical PLIC: complete sources completed
+    /// by guest and follow enable bits of them. Physical sources of other
+    /// guests are untouched.
+    fn after_access(&mut self, host_vmm: &mut HostVmm<PageTableSv39, G>, guest_id: usize) {
+        let context = plic_s_context(host_vmm.hart_id);
+        let enable_dirty = core::mem::take(&mut self.enable_dirty);
+        let completed = core::mem::take(&mut self.completed);
+        if let Some(host_plic) = host_vmm.host_plic.as_mut() {
+            for irq in completed {
+                if host_plic.owner[irq] == Some(guest_id) {
+                    host_plic.complete(context, irq);
                 }
-            } else if index == 1 {
-                // claim/complete
-                // htracking!("claim/complete");
-                match instrution {
-                    Instruction::Lw(i) => {
-                        // guest read claim from plic core
-                        // htracking!(
-                        //     "PLIC read addr@{:#x} -> {:#x}",
-                        //     guest_pa,
-                        //     host_plic.claim_complete[hart]
-                        // );
-                        ctx.x[i.rd() as usize] = host_plic.claim_complete[hart] as usize;
-                    }
-                    Instruction::Sw(i) => {
-                        // guest write complete to plic core
-                        let value = ctx.x[i.rs2() as usize] as u32;
-                        // htracking!("Write plic addr@:{:#x} -> {:#x}", guest_pa, value);
-                        // todo: guest pa -> host pa
-                        unsafe {
-                            core::ptr::write_volatile(guest_pa as *mut u32, value);
-                        }
-                        host_plic.claim_complete[hart] = 0;
-                        unsafe {
-                            hvip::clear_vseip();
-                        }
+            }
+            for word in (0..PLIC_IRQ_WORDS).filter(|word| enable_dirty & 1 << word != 0) {
+                for irq in (word * 32..(word + 1) * 32).filter(|&irq| irq != 0) {
+                    if host_plic.owner[irq] == Some(guest_id) {
+                        host_plic.set_enable(context, irq, self.is_enabled(irq));
                     }
-                    _ => return Err(VmmError::UnexpectedInst),
                 }
             }
+        }
+        let running = guest_id == host_vmm.guest_id;
+        if let Some(guest) = host_vmm.guests[guest_id].as_mut() {
+            update_vcpus_eip(self, &mut guest.vcpus, running.then_some(guest.vcpu_id));
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Update `VSEIP` of each vcpu from S-mode context of virtual PLIC. The
+/// `running` vcpu has `hvip` in CSR, others have it saved in vcpu.
+fn update_vcpus_eip(vplic: &VirtPlic, vcpus: &mut [VCpu], running: Option<usize>) {
+    for (vcpu_id, vcpu) in vcpus.iter_mut().enumerate() {
+        let level = vplic.best_irq(plic_s_context(vcpu.hart)).is_some();
+        if running == Some(vcpu_id) {
+            let bits = hvip::read();
+            let bits = if level { bits | hvip::VSEIP } else { bits & !hvip::VSEIP };
+            unsafe { hvip::write(bits) };
+        } else if level {
+            vcpu.hvip |= hvip::VSEIP;
         } else {
-            panic!("Invalid address: {:#x}", guest_pa);
+            vcpu.hvip &= !hvip::VSEIP;
         }
-        Ok(())
     }
 }
 
-#[inline(always)]
-pub fn is_plic_access(addr: usize) -> bool {
-    // let host_vmm = unsafe{ HOST_VMM.get().unwrap().lock() };
-    // let plic = if let Some(plic) = &host_vmm.host_machine.plic {
-    //     plic
-    // }else{
-    //     return false
-    // };
-    // if addr >= plic.base_address && addr <= plic.base_address + plic.size { return true }
-    // else{ return false }
-
-    // TODO: use guest machine base address
-    return addr >= 0x0c00_0000 && addr < 0x1000_0000;
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    /// route physical source `irq` to guest, return false if it is owned by another guest
+    pub fn assign_irq(&mut self, irq: usize, guest_id: usize) -> bool {
+        let Some(host_plic) = self.host_plic.as_mut() else {
+            return false;
+        };
+        if irq == 0 || irq >= PLIC_MAX_IRQS {
+            hwarning!("invalid irq {} for guest {}", irq, guest_id);
+            return false;
+        }
+        match host_plic.owner[irq] {
+            Some(owner) if owner != guest_id => {
+                hwarning!("irq {} is owned by guest {}, guest {} can not use it", irq, owner, guest_id);
+                false
+            }
+            _ => {
+                hdebug!("route irq {} to guest {}", irq, guest_id);
+                host_plic.owner[irq] = Some(guest_id);
+                // priority is arbitrated in virtual PLIC, physical source is
+                // enabled when guest enables it
+                host_plic.set_priority(irq, 1);
+                true
+            }
+        }
+    }
+
+    /// drop all sources routed to guest
+    pub fn release_guest_irqs(&mut self, guest_id: usize) {
+        let context = plic_s_context(self.hart_id);
+        let Some(host_plic) = self.host_plic.as_mut() else {
+            return;
+        };
+        for irq in 1..PLIC_MAX_IRQS {
+            if host_plic.owner[irq] == Some(guest_id) {
+                host_plic.owner[irq] = None;
+                host_plic.set_enable(context, irq, false);
+            }
+        }
+    }
+
+    /// claim a physical interrupt in hypervisor context and make it pending
+    /// in virtual PLIC of its owner
+    pub fn handle_plic_irq(&mut self) {
+        let context = plic_s_context(self.hart_id);
+        let Some(host_plic) = self.host_plic.as_mut() else {
+            return;
+        };
+        let irq = host_plic.claim(context);
+        if irq == 0 {
+            return;
+        }
+        let owner = host_plic.owner[irq];
+        let vplic = owner
+            .and_then(|owner| self.guests[owner].as_mut())
+            .and_then(|guest| guest.mmio.device_mut::<VirtPlic>());
+        match (owner, vplic) {
+            (Some(owner), Some(vplic)) => {
+                vplic.set_pending(irq, true);
+                self.update_guest_eip(owner);
+            }
+            _ => {
+                hwarning!("irq {} has no owner", irq);
+                host_plic.complete(context, irq);
+            }
+        }
+    }
+
+    /// update `VSEIP` of vcpus of guest from its virtual PLIC
+    pub fn update_guest_eip(&mut self, guest_id: usize) {
+        let running = guest_id == self.guest_id;
+        let Some(guest) = self.guests[guest_id].as_mut() else {
+            return;
+        };
+        let running = running.then_some(guest.vcpu_id);
+        let Some(vplic) = guest.mmio.device::<VirtPlic>() else {
+            return;
+        };
+        update_vcpus_eip(vplic, &mut guest.vcpus, running);
+    }
+
+    /// Give back physical sources held by guest, must be called before
+    /// guest is rebooted or torn down.
+    pub fn reset_guest_plic(&mut self, guest_id: usize) {
+        let context = plic_s_context(self.hart_id);
+        let (Some(host_plic), Some(guest)) = (self.host_plic.as_mut(), self.guests[guest_id].as_mut()) else {
+            return;
+        };
+        let Some(vplic) = guest.mmio.device_mut::<VirtPlic>() else {
+            return;
+        };
+        for irq in vplic.active_irqs() {
+            if host_plic.owner[irq] == Some(guest_id) {
+                host_plic.complete(context, irq);
+            }
+        }
+        // sources stay routed to guest but are masked until enabled again
+        for irq in 1..PLIC_MAX_IRQS {
+            if host_plic.owner[irq] == Some(guest_id) {
+                host_plic.set_enable(context, irq, false);
+            }
+        }
+        vplic.reset();
+    }
 }
@@ -0,0 +1,57 @@
+//! Emulated sifive test finisher, which only powers off or resets the guest writing it
+
+use core::any::Any;
+use crate::device_emu::mmio::MmioDevice;
+use crate::{guest::page_table::GuestPageTable, hypervisor::HostVmm, page_table::PageTableSv39};
+use crate::VmmResult;
+
+pub const TEST_FINISHER_FAIL: u32 = 0x3333;
+pub const TEST_FINISHER_PASS: u32 = 0x5555;
+pub const TEST_FINISHER_RESET: u32 = 0x7777;
+
+pub struct TestFinisher;
+
+impl<G: GuestPageTable> MmioDevice<G> for TestFinisher {
+    fn read(
+        &mut self,
+        _host_vmm: &mut HostVmm<PageTableSv39, G>,
+        _guest_id: usize,
+        _offset: usize,
+        _width: usize,
+    ) -> VmmResult<usize> {
+        Ok(0)
+    }
+
+    fn write(
+        &mut self,
+        host_vmm: &mut HostVmm<PageTableSv39, G>,
+        guest_id: usize,
+        offset: usize,
+        width: usize,
+        value: usize,
+    ) -> VmmResult {
+        if width != 4 {
+            hwarning!("test finisher: {}-byte write at {:#x} ignored", width, offset);
+            return Ok(());
+        }
+        let value = value as u32;
+        match value & 0xffff {
+            TEST_FINISHER_PASS => host_vmm.shutdown_guest(guest_id),
+            TEST_FINISHER_FAIL => {
+                herror!("guest {} exit with code {}", guest_id, value >> 16);
+                host_vmm.shutdown_guest(guest_id);
+            }
+            TEST_FINISHER_RESET => host_vmm.reboot_guest(guest_id),
+            _ => hwarning!("guest {} writes test finisher: {:#x}", guest_id, value),
+        }
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
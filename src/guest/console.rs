@@ -0,0 +1,46 @@
+//! Per guest console, output of different guests is prefixed by guest id
+//! so that consoles of several guests do not interleave in one line.
+
+use crate::hypervisor::HostVmm;
+use crate::page_table::PageTable;
+use crate::sbi::console_putchar;
+
+use super::page_table::GuestPageTable;
+
+pub struct GuestConsole {
+    /// whether next output byte starts a new line
+    line_start: bool,
+}
+
+impl GuestConsole {
+    pub fn new() -> Self {
+        Self { line_start: true }
+    }
+}
+
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    /// write a byte from current guest to host console
+    pub fn guest_console_putchar(&mut self, c: u8) {
+        let guest_id = self.guest_id;
+        if self.console_owner != guest_id {
+            // finish the line of previous guest
+            if let Some(prev) = self.guests[self.console_owner].as_mut() {
+                if !prev.console.line_start {
+                    console_putchar('\n' as usize);
+                    prev.console.line_start = true;
+                }
+            }
+            self.console_owner = guest_id;
+        }
+        let multi_guests = self.guests.iter().filter(|guest| guest.is_some()).count() > 1;
+        let guest = match self.guests[guest_id].as_mut() {
+            Some(guest) => guest,
+            None => return,
+        };
+        if guest.console.line_start && multi_guests {
+            print!("[Guest {}] ", guest_id);
+        }
+        console_putchar(c as usize);
+        guest.console.line_start = c == b'\n';
+    }
+}
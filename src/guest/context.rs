@@ -1,15 +1,27 @@
 use crate::constants::riscv_regs::{ GeneralPurposeRegisters, GprIndex };
+use crate::constants::csr::{ henvcfg, vstimecmp };
 use memoffset::offset_of;
 use core::mem::size_of;
-use core::arch::global_asm;
+use core::arch::{asm, global_asm};
+use alloc::vec;
+use alloc::vec::Vec;
 
 use riscv::register::{
     sstatus::{self, Sstatus, SPP },
     hstatus::{self, Hstatus }
 };
 
+/// FS and VS fields of `sstatus`, set to dirty by hardware once guest
+/// changes FP or vector registers
+const SSTATUS_FS: usize = 0b11 << 13;
+const SSTATUS_FS_INITIAL: usize = 0b01 << 13;
+const SSTATUS_FS_CLEAN: usize = 0b10 << 13;
+const SSTATUS_VS: usize = 0b11 << 9;
+const SSTATUS_VS_INITIAL: usize = 0b01 << 9;
+const SSTATUS_VS_CLEAN: usize = 0b10 << 9;
+
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 /// trap context structure containing sstatus, sepc and registers
 pub struct TrapContext {
     /// general regs[0..31]
@@ -34,6 +46,11 @@ impl TrapContext {
         self.x[2] = sp;
     }
 
+    /// raw bits of `sstatus`, FS and VS are not exposed by `Sstatus`
+    fn sstatus_bits(&mut self) -> &mut usize {
+        unsafe { &mut *(&mut self.sstatus as *mut Sstatus as *mut usize) }
+    }
+
     /// init guest trap context
     pub fn initialize_context(
         entry: usize,
@@ -57,6 +74,13 @@ impl TrapContext {
             hstatus
         };
         cx.set_sp(sp); // app's user stack pointer
+        // FP and vector registers of guest start from zero
+        let status = cx.sstatus_bits();
+        *status = *status & !SSTATUS_FS | SSTATUS_FS_INITIAL;
+        *status &= !SSTATUS_VS;
+        if has_vector() {
+            *status |= SSTATUS_VS_INITIAL;
+        }
         cx // return initial Trap Context of app
     }
 }
@@ -86,10 +110,9 @@ struct GuestCpuState {
 
 /// The CSRs that are only in effect when virtualization is enabled (V=1) and must be saved and
 /// restored whenever we switch between VMs.
-#[derive(Default)]
+#[derive(Clone)]
 #[repr(C)]
 pub struct GuestVsCsrs {
-    htimedelta: u64,
     vsstatus: u64,
     vsie: u64,
     vstvec: u64,
@@ -101,6 +124,351 @@ pub struct GuestVsCsrs {
     vstimecmp: u64,
 }
 
+impl Default for GuestVsCsrs {
+    fn default() -> Self {
+        Self {
+            vsstatus: 0,
+            vsie: 0,
+            vstvec: 0,
+            vsscratch: 0,
+            vsepc: 0,
+            vscause: 0,
+            vstval: 0,
+            vsatp: 0,
+            // no timer interrupt until guest sets one
+            vstimecmp: u64::MAX,
+        }
+    }
+}
+
+impl GuestVsCsrs {
+    /// save VS-level CSRs of the running vcpu
+    pub fn save(&mut self) {
+        unsafe {
+            core::arch::asm!(
+                "csrr {vsstatus}, vsstatus",
+                "csrr {vsie}, vsie",
+                "csrr {vstvec}, vstvec",
+                "csrr {vsscratch}, vsscratch",
+                "csrr {vsepc}, vsepc",
+                "csrr {vscause}, vscause",
+                "csrr {vstval}, vstval",
+                "csrr {vsatp}, vsatp",
+                vsstatus = out(reg) self.vsstatus,
+                vsie = out(reg) self.vsie,
+                vstvec = out(reg) self.vstvec,
+                vsscratch = out(reg) self.vsscratch,
+                vsepc = out(reg) self.vsepc,
+                vscause = out(reg) self.vscause,
+                vstval = out(reg) self.vstval,
+                vsatp = out(reg) self.vsatp,
+            );
+            // `vstimecmp` exists when Sstc is enabled
+            if henvcfg::read() & henvcfg::STCE != 0 {
+                core::arch::asm!("csrr {}, 0x24d", out(reg) self.vstimecmp);
+            }
+        }
+    }
+
+    /// restore VS-level CSRs of the vcpu which will be run
+    pub fn restore(&self) {
+        unsafe {
+            core::arch::asm!(
+                "csrw vsstatus, {vsstatus}",
+                "csrw vsie, {vsie}",
+                "csrw vstvec, {vstvec}",
+                "csrw vsscratch, {vsscratch}",
+                "csrw vsepc, {vsepc}",
+                "csrw vscause, {vscause}",
+                "csrw vstval, {vstval}",
+                "csrw vsatp, {vsatp}",
+                vsstatus = in(reg) self.vsstatus,
+                vsie = in(reg) self.vsie,
+                vstvec = in(reg) self.vstvec,
+                vsscratch = in(reg) self.vsscratch,
+                vsepc = in(reg) self.vsepc,
+                vscause = in(reg) self.vscause,
+                vstval = in(reg) self.vstval,
+                vsatp = in(reg) self.vsatp,
+            );
+            if henvcfg::read() & henvcfg::STCE != 0 {
+                vstimecmp::write(self.vstimecmp as usize);
+            }
+        }
+    }
+}
+
+/// `vlenb` if V extension exists, VS of `sstatus` is read-only zero without
+/// it. VS of hypervisor is put back as it was.
+fn probe_vlenb() -> Option<usize> {
+    let (old, status): (usize, usize);
+    unsafe {
+        asm!(
+            "csrrs {old}, sstatus, {vs}",
+            "csrr {status}, sstatus",
+            vs = in(reg) SSTATUS_VS,
+            old = out(reg) old,
+            status = out(reg) status,
+        );
+    }
+    let vlenb = if status & SSTATUS_VS != 0 {
+        let vlenb: usize;
+        unsafe { asm!("csrr {}, 0xc22", out(reg) vlenb) };
+        Some(vlenb)
+    } else {
+        None
+    };
+    unsafe {
+        asm!(
+            "csrc sstatus, {vs}",
+            "csrs sstatus, {old}",
+            vs = in(reg) SSTATUS_VS,
+            old = in(reg) old & SSTATUS_VS,
+        );
+    }
+    vlenb
+}
+
+/// whether V extension exists
+fn has_vector() -> bool {
+    probe_vlenb().is_some()
+}
+
+/// FP registers of a vcpu, saved only if guest dirtied them
+#[derive(Clone, Default)]
+#[repr(C)]
+pub struct GuestFpState {
+    f: [u64; 32],
+    fcsr: u64,
+}
+
+impl GuestFpState {
+    /// save FP registers of the running vcpu if guest dirtied them since last
+    /// saved or restored, which are clean in `ctx` then
+    pub fn save(&mut self, ctx: &mut TrapContext) {
+        let status = ctx.sstatus_bits();
+        if *status & SSTATUS_FS != SSTATUS_FS {
+            return;
+        }
+        unsafe {
+            asm!(
+                "csrs sstatus, {fs}",
+                "fsd f0, 0({f})",
+                "fsd f1, 8({f})",
+                "fsd f2, 16({f})",
+                "fsd f3, 24({f})",
+                "fsd f4, 32({f})",
+                "fsd f5, 40({f})",
+                "fsd f6, 48({f})",
+                "fsd f7, 56({f})",
+                "fsd f8, 64({f})",
+                "fsd f9, 72({f})",
+                "fsd f10, 80({f})",
+                "fsd f11, 88({f})",
+                "fsd f12, 96({f})",
+                "fsd f13, 104({f})",
+                "fsd f14, 112({f})",
+                "fsd f15, 120({f})",
+                "fsd f16, 128({f})",
+                "fsd f17, 136({f})",
+                "fsd f18, 144({f})",
+                "fsd f19, 152({f})",
+                "fsd f20, 160({f})",
+                "fsd f21, 168({f})",
+                "fsd f22, 176({f})",
+                "fsd f23, 184({f})",
+                "fsd f24, 192({f})",
+                "fsd f25, 200({f})",
+                "fsd f26, 208({f})",
+                "fsd f27, 216({f})",
+                "fsd f28, 224({f})",
+                "fsd f29, 232({f})",
+                "fsd f30, 240({f})",
+                "fsd f31, 248({f})",
+                "frcsr {fcsr}",
+                // FP stays off while hypervisor runs
+                "csrc sstatus, {fs}",
+                fs = in(reg) SSTATUS_FS,
+                f = in(reg) self.f.as_mut_ptr(),
+                fcsr = out(reg) self.fcsr,
+            );
+        }
+        *status = *status & !SSTATUS_FS | SSTATUS_FS_CLEAN;
+    }
+
+    /// restore FP registers of the vcpu which will be run, unless guest has FP off
+    pub fn restore(&self, ctx: &mut TrapContext) {
+        let status = ctx.sstatus_bits();
+        if *status & SSTATUS_FS == 0 {
+            return;
+        }
+        unsafe {
+            asm!(
+                "csrs sstatus, {fs}",
+                "fld f0, 0({f})",
+                "fld f1, 8({f})",
+                "fld f2, 16({f})",
+                "fld f3, 24({f})",
+                "fld f4, 32({f})",
+                "fld f5, 40({f})",
+                "fld f6, 48({f})",
+                "fld f7, 56({f})",
+                "fld f8, 64({f})",
+                "fld f9, 72({f})",
+                "fld f10, 80({f})",
+                "fld f11, 88({f})",
+                "fld f12, 96({f})",
+                "fld f13, 104({f})",
+                "fld f14, 112({f})",
+                "fld f15, 120({f})",
+                "fld f16, 128({f})",
+                "fld f17, 136({f})",
+                "fld f18, 144({f})",
+                "fld f19, 152({f})",
+                "fld f20, 160({f})",
+                "fld f21, 168({f})",
+                "fld f22, 176({f})",
+                "fld f23, 184({f})",
+                "fld f24, 192({f})",
+                "fld f25, 200({f})",
+                "fld f26, 208({f})",
+                "fld f27, 216({f})",
+                "fld f28, 224({f})",
+                "fld f29, 232({f})",
+                "fld f30, 240({f})",
+                "fld f31, 248({f})",
+                "fscsr {fcsr}",
+                // FP stays off while hypervisor runs
+                "csrc sstatus, {fs}",
+                fs = in(reg) SSTATUS_FS,
+                f = in(reg) self.f.as_ptr(),
+                fcsr = in(reg) self.fcsr,
+            );
+        }
+        *status = *status & !SSTATUS_FS | SSTATUS_FS_CLEAN;
+    }
+}
+
+/// vector registers of a vcpu, `v0`-`v31` are `vlenb` bytes each
+#[derive(Clone)]
+pub struct GuestVectorState {
+    /// allocated once when vcpu is created, empty without V extension
+    vregs: Vec<u8>,
+    vlenb: usize,
+    vstart: usize,
+    vtype: usize,
+    vl: usize,
+    vcsr: usize,
+}
+
+impl GuestVectorState {
+    pub fn new() -> Self {
+        let vlenb = probe_vlenb().unwrap_or(0);
+        Self {
+            vregs: vec![0; 32 * vlenb],
+            vlenb,
+            vstart: 0,
+            vtype: 0,
+            vl: 0,
+            vcsr: 0,
+        }
+    }
+
+    /// vector registers of restarted vcpu start from zero
+    pub fn reset(&mut self) {
+        self.vregs.fill(0);
+        self.vstart = 0;
+        self.vtype = 0;
+        self.vl = 0;
+        self.vcsr = 0;
+    }
+
+    /// enable vector unit of hypervisor
+    fn enable(&self) {
+        unsafe { asm!("csrs sstatus, {vs}", vs = in(reg) SSTATUS_VS) };
+    }
+
+    /// vector unit stays off while hypervisor runs
+    fn disable(&self) {
+        unsafe { asm!("csrc sstatus, {vs}", vs = in(reg) SSTATUS_VS) };
+    }
+
+    /// save vector registers of the running vcpu if guest dirtied them since
+    /// last saved or restored, which are clean in `ctx` then
+    pub fn save(&mut self, ctx: &mut TrapContext) {
+        let status = ctx.sstatus_bits();
+        if *status & SSTATUS_VS != SSTATUS_VS {
+            return;
+        }
+        self.enable();
+        unsafe {
+            asm!(
+                ".option push",
+                ".option arch, +v",
+                "csrr {vstart}, 0x008",
+                "csrr {vtype}, 0xc21",
+                "csrr {vl}, 0xc20",
+                "csrr {vcsr}, 0x00f",
+                // whole register groups are stored regardless of `vl`
+                "vsetvli {tmp}, x0, e8, m8, ta, ma",
+                "vs8r.v v0, ({addr})",
+                "add {addr}, {addr}, {stride}",
+                "vs8r.v v8, ({addr})",
+                "add {addr}, {addr}, {stride}",
+                "vs8r.v v16, ({addr})",
+                "add {addr}, {addr}, {stride}",
+                "vs8r.v v24, ({addr})",
+                ".option pop",
+                vstart = out(reg) self.vstart,
+                vtype = out(reg) self.vtype,
+                vl = out(reg) self.vl,
+                vcsr = out(reg) self.vcsr,
+                tmp = out(reg) _,
+                addr = inout(reg) self.vregs.as_mut_ptr() => _,
+                stride = in(reg) 8 * self.vlenb,
+            );
+        }
+        self.disable();
+        *status = *status & !SSTATUS_VS | SSTATUS_VS_CLEAN;
+    }
+
+    /// restore vector registers of the vcpu which will be run, unless guest has vector off
+    pub fn restore(&self, ctx: &mut TrapContext) {
+        let status = ctx.sstatus_bits();
+        if *status & SSTATUS_VS == 0 {
+            return;
+        }
+        self.enable();
+        unsafe {
+            asm!(
+                ".option push",
+                ".option arch, +v",
+                "vsetvli {tmp}, x0, e8, m8, ta, ma",
+                "vl8re8.v v0, ({addr})",
+                "add {addr}, {addr}, {stride}",
+                "vl8re8.v v8, ({addr})",
+                "add {addr}, {addr}, {stride}",
+                "vl8re8.v v16, ({addr})",
+                "add {addr}, {addr}, {stride}",
+                "vl8re8.v v24, ({addr})",
+                "vsetvl x0, {vl}, {vtype}",
+                "csrw 0x008, {vstart}",
+                "csrw 0x00f, {vcsr}",
+                ".option pop",
+                tmp = out(reg) _,
+                addr = inout(reg) self.vregs.as_ptr() => _,
+                stride = in(reg) 8 * self.vlenb,
+                vl = in(reg) self.vl,
+                vtype = in(reg) self.vtype,
+                vstart = in(reg) self.vstart,
+                vcsr = in(reg) self.vcsr,
+            );
+        }
+        self.disable();
+        *status = *status & !SSTATUS_VS | SSTATUS_VS_CLEAN;
+    }
+}
+
 /// Virtualized HS-level CSRs that are used to emulate (part of) the hypervisor extension for the
 /// guest.
 #[derive(Default)]
@@ -0,0 +1,65 @@
+//! Pristine copies of guest kernel image and device tree, used to reboot guest
+
+use alloc::vec::Vec;
+
+use crate::constants::PAGE_SIZE;
+use crate::hyp_alloc::{frame_alloc, FrameTracker};
+
+/// copy of a guest physical memory region saved in hypervisor frames
+struct RegionBackup {
+    addr: usize,
+    size: usize,
+    frames: Vec<FrameTracker>,
+}
+
+impl RegionBackup {
+    /// save `[addr, addr + size)`, region must be mapped in hypervisor
+    fn save(addr: usize, size: usize) -> Self {
+        let mut frames = Vec::new();
+        for offset in (0..size).step_by(PAGE_SIZE) {
+            let frame = frame_alloc().unwrap();
+            let len = PAGE_SIZE.min(size - offset);
+            let src = unsafe{ core::slice::from_raw_parts((addr + offset) as *const u8, len) };
+            frame.ppn.get_bytes_array()[..len].copy_from_slice(src);
+            frames.push(frame);
+        }
+        Self { addr, size, frames }
+    }
+
+    fn restore(&self) {
+        for (i, frame) in self.frames.iter().enumerate() {
+            let offset = i * PAGE_SIZE;
+            let len = PAGE_SIZE.min(self.size - offset);
+            let dst = unsafe{ core::slice::from_raw_parts_mut((self.addr + offset) as *mut u8, len) };
+            dst.copy_from_slice(&frame.ppn.get_bytes_array()[..len]);
+        }
+    }
+}
+
+pub struct GuestImage {
+    kernel: RegionBackup,
+    dtb: RegionBackup,
+}
+
+impl GuestImage {
+    /// save guest kernel and dtb before guest runs
+    pub fn new(kernel_addr: usize, kernel_size: usize, dtb_addr: usize, dtb_size: usize) -> Self {
+        hdebug!(
+            "save guest image: kernel [{:#x}: {:#x}), dtb [{:#x}: {:#x})",
+            kernel_addr,
+            kernel_addr + kernel_size,
+            dtb_addr,
+            dtb_addr + dtb_size
+        );
+        Self {
+            kernel: RegionBackup::save(kernel_addr, kernel_size),
+            dtb: RegionBackup::save(dtb_addr, dtb_size),
+        }
+    }
+
+    /// reload guest kernel and dtb
+    pub fn reload(&self) {
+        self.kernel.restore();
+        self.dtb.restore();
+    }
+}
@@ -1,16 +1,52 @@
-use crate::constants::layout::{TRAP_CONTEXT, GUEST_START_VA};
+use arrayvec::ArrayVec;
+
+use crate::constants::MAX_GUEST_HARTS;
+use crate::constants::csr::{hvip, htimedelta};
+use riscv::register::time;
+use crate::constants::layout::{TRAP_CONTEXT, GUEST_START_VA, GUEST_DTB_ADDR};
+use core::arch::riscv64::hfence_vvma_all;
 use crate::hypervisor::fdt::MachineMeta;
+use crate::device_emu::aplic::VirtAplic;
+use crate::device_emu::clint::VirtClint;
+use crate::device_emu::mmio::MmioRegistry;
+use crate::device_emu::plic::VirtPlic;
+use crate::device_emu::test_finisher::TestFinisher;
+use alloc::boxed::Box;
 use crate::mm::{ GuestMemorySet, MemorySet };
-use crate::hypervisor::{ stack::hstack_alloc};
+use crate::page_table::VirtPageNum;
+use crate::hypervisor::{ stack::{hstack_alloc, HypervisorStack} };
 use vmexit::{TrapContext, trap_handler};
 
 use self::page_table::GuestPageTable;
-use self::vcpu::VCpu;
-pub use sbi::SbiRet;
+use self::console::GuestConsole;
+use self::pmu::GuestPmu;
+pub use self::image::GuestImage;
+pub use self::vcpu::{VCpu, VCpuState, VCPU_EVENT_IPI, VCPU_EVENT_TIMER, VCPU_EVENT_GEI, VCPU_EVENT_WAKE};
+pub use sbi::{SbiExtension, SbiRegistry, SbiRet};
+
+/// VMID field offset of `hgatp`
+const HGATP_VMID_SHIFT: usize = 44;
+/// VMID field of `hgatp`, at most 14 bits on RV64
+const HGATP_VMID_MASK: usize = 0x3fff << HGATP_VMID_SHIFT;
 
+/// VMIDLEN of hart, found by writing ones to VMID of `hgatp` and counting the
+/// bits which hold. May be zero.
+pub fn probe_vmid_bits() -> usize {
+    let old = riscv::register::hgatp::read().bits();
+    riscv::register::hgatp::Hgatp::from_bits(old | HGATP_VMID_MASK).write();
+    let vmid = riscv::register::hgatp::read().bits() & HGATP_VMID_MASK;
+    riscv::register::hgatp::Hgatp::from_bits(old).write();
+    unsafe { core::arch::riscv64::hfence_gvma_all() };
+    (vmid >> HGATP_VMID_SHIFT).count_ones() as usize
+}
+
+mod console;
 mod context;
+mod image;
+pub mod pmu;
 mod vcpu;
 mod sbi;
+mod sta;
 pub mod vmexit;
 
 
@@ -20,31 +56,79 @@ pub struct Guest<G: GuestPageTable> {
     pub gpm: GuestMemorySet<G>,
     /// guest id
     pub guest_id: usize,
+    /// VMID written into `hgatp`, tags guest TLB entries
+    pub vmid: usize,
+    /// hypervisor stack used when trapping from guest
+    pub hstack: HypervisorStack,
+    /// saved kernel image and dtb for reboot
+    pub image: Option<GuestImage>,
+    /// guest console state
+    pub console: GuestConsole,
+    /// SBI extensions visible to guest
+    pub sbi: SbiRegistry<G>,
+    /// performance counters of guest
+    pub pmu: GuestPmu,
+    /// misaligned loads and stores emulated for guest
+    pub misaligned_loads: usize,
+    pub misaligned_stores: usize,
+    /// devices emulated on MMIO, virtual PLIC, APLIC and CLINT included
+    pub mmio: MmioRegistry<G>,
+    /// time base of guest written into `htimedelta`, guest time = host time + time_delta
+    pub time_delta: usize,
+    /// host time when guest clock was paused
+    pub paused_at: Option<usize>,
     /// virtual cpu status
-    pub vcpu: VCpu
+    pub vcpus: ArrayVec<VCpu, MAX_GUEST_HARTS>,
+    /// current running vcpu id
+    pub vcpu_id: usize
 }
 
 impl<G: GuestPageTable> Guest<G> {
-    pub fn new(guest_id: usize, gpm: GuestMemorySet<G>, guest_machine: MachineMeta) -> Self {
+    pub fn new(guest_id: usize, gpm: GuestMemorySet<G>, guest_machine: MachineMeta, image: Option<GuestImage>) -> Self {
         // 分配 hypervisor 内核栈
         let hstack = hstack_alloc(guest_id);
-        let hstack_top = hstack.get_top();
-        // 获取 trap context
-        let trap_ctx: &mut TrapContext = unsafe{ (TRAP_CONTEXT as *mut TrapContext).as_mut().unwrap() };
-        // 初始化 trap context 的环境
-        // 包括入口地址/栈寄存器/satp/内核栈寄存器/trap处理地址
-        *trap_ctx = TrapContext::initialize_context(
-            GUEST_START_VA,
-            0,
-            gpm.token(),
-            hstack_top,
-            trap_handler as usize
-        );
+        // boot vcpu enters guest kernel with `a0` is hart id and `a1` is dtb address,
+        // other vcpus wait for `hart_start`.
+        // all vcpus are bound to physical hart 0 currently(single core)
+        let mut vcpus = ArrayVec::new();
+        for hart in 0..guest_machine.harts.max(1).min(MAX_GUEST_HARTS) {
+            vcpus.push(VCpu::new(hart, 0));
+        }
+        vcpus[0].start_addr = GUEST_START_VA;
+        vcpus[0].opaque = GUEST_DTB_ADDR;
+        vcpus[0].state = VCpuState::StartPending;
+        let mut mmio = MmioRegistry::new();
+        if let Some(plic) = guest_machine.plic.as_ref() {
+            mmio.register(plic.base_address, plic.size, Box::new(VirtPlic::new(2 * vcpus.len())));
+        }
+        if let Some(aplic) = guest_machine.aplic.as_ref() {
+            mmio.register(aplic.base_address, aplic.size, Box::new(VirtAplic::new()));
+        }
+        if let Some(clint) = guest_machine.clint.as_ref() {
+            mmio.register(clint.base_address, clint.size, Box::new(VirtClint));
+        }
+        if let Some(test) = guest_machine.test_finisher_address.as_ref() {
+            mmio.register(test.base_address, test.size, Box::new(TestFinisher));
+        }
+        let sbi = SbiRegistry::with_config(&guest_machine.sbi_enable, &guest_machine.sbi_disable);
         Self {
             guest_id,
+            // assigned when guest is added to hypervisor
+            vmid: 0,
             gpm,
             guest_machine,
-            vcpu: VCpu::new(guest_id),
+            hstack,
+            image,
+            console: GuestConsole::new(),
+            sbi,
+            pmu: GuestPmu::new(),
+            misaligned_loads: 0,
+            misaligned_stores: 0,
+            mmio,
+            time_delta: boot_time_delta(),
+            paused_at: None,
+            vcpus,
+            vcpu_id: 0
         }
     }
 
@@ -52,6 +136,214 @@ impl<G: GuestPageTable> Guest<G> {
     pub fn run(&mut self) {
         todo!()
     }
+
+    /// convert guest timer deadline to host time, `usize::MAX` means no timer
+    pub fn guest_time_to_host(&self, deadline: usize) -> usize {
+        if deadline == usize::MAX {
+            return deadline;
+        }
+        deadline.wrapping_sub(self.time_delta)
+    }
+
+    /// stop guest clock, guest does not see time passing until `resume_clock`
+    pub fn pause_clock(&mut self) {
+        if self.paused_at.is_none() {
+            self.paused_at = Some(time::read());
+        }
+    }
+
+    /// whether guest is paused by hypervisor, its clock stands still
+    pub fn is_paused(&self) -> bool {
+        self.paused_at.is_some()
+    }
+
+    /// resume guest clock from where it was paused, return how long it was paused
+    pub fn resume_clock(&mut self) -> usize {
+        let Some(paused_at) = self.paused_at.take() else {
+            return 0;
+        };
+        let paused = time::read().wrapping_sub(paused_at);
+        self.time_delta = self.time_delta.wrapping_sub(paused);
+        paused
+    }
+
+    /// `hgatp` value of guest, with VMID
+    pub fn hgatp(&self) -> usize {
+        self.gpm.token() | self.vmid << HGATP_VMID_SHIFT
+    }
+
+    /// whether `guest_pa` is in RAM described by memory node of guest
+    pub fn is_ram(&self, guest_pa: usize) -> bool {
+        let start = self.guest_machine.physical_memory_offset;
+        let end = start + self.guest_machine.physical_memory_size;
+        guest_pa >= start && guest_pa < end
+    }
+
+    /// whether guest may execute at `guest_pa` through G-stage, which is
+    /// required of start address of `hart_start` and `hart_suspend`
+    pub fn is_executable(&self, guest_pa: usize) -> bool {
+        self.gpm
+            .translate(VirtPageNum::from(guest_pa >> 12))
+            .map_or(false, |pte| pte.is_valid() && pte.executable())
+    }
+
+    /// physical harts which run vcpus in `vcpu_mask`
+    pub fn phys_hart_mask(&self, vcpu_mask: usize) -> usize {
+        self.vcpus
+            .iter()
+            .filter(|vcpu| vcpu_mask & (1 << vcpu.hart) != 0)
+            .fold(0, |mask, vcpu| mask | 1 << vcpu.phys_hart)
+    }
+
+    /// flush guest TLB of vcpus in `vcpu_mask` placed on other physical harts
+    /// than `hart_id` on their next entry
+    pub fn defer_tlb_flush(&mut self, vcpu_mask: usize, hart_id: usize) {
+        for vcpu in self.vcpus.iter_mut() {
+            if vcpu_mask & (1 << vcpu.hart) != 0 && vcpu.phys_hart != hart_id {
+                vcpu.tlb_flush = true;
+            }
+        }
+    }
+
+    /// next runnable vcpu in round robin, including current vcpu,
+    /// nothing runs while guest is paused
+    pub fn next_runnable_vcpu(&self) -> Option<usize> {
+        if self.is_paused() {
+            return None;
+        }
+        let nr_vcpus = self.vcpus.len();
+        (1..=nr_vcpus)
+            .map(|i| (self.vcpu_id + i) % nr_vcpus)
+            .find(|&next| self.vcpus[next].is_runnable())
+    }
+
+    /// pick next runnable vcpu in round robin and switch to it,
+    /// return false if no vcpu can be run
+    pub fn schedule(&mut self) -> bool {
+        if let Some(next) = self.next_runnable_vcpu() {
+            self.switch_vcpu(next);
+            return true;
+        }
+        // Suspended vcpu is allowed to wake up spuriously like `wfi`,
+        // so resume it when there is nothing else to run.
+        if self.vcpus[self.vcpu_id].state == VCpuState::Suspended {
+            self.switch_vcpu(self.vcpu_id);
+            return true;
+        }
+        false
+    }
+
+    /// queue software interrupt on all vcpus in `vcpu_mask`
+    pub fn send_ipi(&mut self, vcpu_mask: usize) {
+        for vcpu in self.vcpus.iter_mut() {
+            if vcpu_mask & (1 << vcpu.hart) != 0 {
+                vcpu.push_event(VCPU_EVENT_IPI);
+            }
+        }
+    }
+
+    /// Reload guest image and restart boot vcpu from `GUEST_START_VA`,
+    /// must be called when guest is running on current hart.
+    pub fn reboot(&mut self) {
+        if let Some(image) = &self.image {
+            image.reload();
+        } else {
+            hwarning!("guest {} has no saved image, restart without reloading", self.guest_id);
+        }
+        for vcpu in self.vcpus.iter_mut() {
+            vcpu.state = VCpuState::Stopped;
+            vcpu.ctx = None;
+            vcpu.hvip = 0;
+            vcpu.pending_events.clear();
+            vcpu.wfi = false;
+            vcpu.sta.disable();
+            vcpu.mtimecmp = usize::MAX;
+        }
+        let vcpu = &mut self.vcpus[0];
+        vcpu.start_addr = GUEST_START_VA;
+        vcpu.opaque = GUEST_DTB_ADDR;
+        vcpu.non_retentive = false;
+        vcpu.state = VCpuState::StartPending;
+        self.pmu.reset();
+        // rebooted guest sees its clock start from zero again
+        self.time_delta = boot_time_delta();
+        self.paused_at = None;
+        // drop stale translations of the old kernel
+        unsafe{ hfence_vvma_all() };
+    }
+
+    /// save the context of running vcpu
+    pub fn save_vcpu(&mut self) {
+        let trap_ctx: &mut TrapContext = unsafe{ (TRAP_CONTEXT as *mut TrapContext).as_mut().unwrap() };
+        let vcpu = &mut self.vcpus[self.vcpu_id];
+        // FP and vector registers are saved lazily, only if guest dirtied them
+        vcpu.fp.save(trap_ctx);
+        vcpu.vector.save(trap_ctx);
+        vcpu.ctx = Some(trap_ctx.clone());
+        vcpu.vs_csrs.save();
+        vcpu.hvip = hvip::read();
+        // vcpu parked in `wfi` gave up the hart, it is not preempted
+        if vcpu.state == VCpuState::Started && !vcpu.wfi {
+            vcpu.sta.preempt();
+        }
+    }
+
+    /// load the context of `next`, VS-level CSRs, FP and vector registers are
+    /// restored if `restore_csrs` or the vcpu is (re)started
+    pub fn load_vcpu(&mut self, next: usize, restore_csrs: bool) {
+        let trap_ctx: &mut TrapContext = unsafe{ (TRAP_CONTEXT as *mut TrapContext).as_mut().unwrap() };
+        let hgatp = self.hgatp();
+        let kernel_sp = self.hstack.get_top();
+        let vcpu = &mut self.vcpus[next];
+        let mut reset = false;
+        match vcpu.state {
+            VCpuState::StartPending => {
+                vcpu.reset_context(hgatp, kernel_sp, trap_handler as usize);
+                reset = true;
+            }
+            VCpuState::Suspended if vcpu.non_retentive => {
+                vcpu.reset_context(hgatp, kernel_sp, trap_handler as usize);
+                reset = true;
+            }
+            _ => {}
+        }
+        vcpu.state = VCpuState::Started;
+        vcpu.wfi = false;
+        vcpu.sta.resume();
+        if let Some(ctx) = vcpu.ctx.take() {
+            *trap_ctx = ctx;
+        }
+        // select IMSIC guest interrupt file of vcpu
+        trap_ctx.hstatus.set_vgein(vcpu.vgein.unwrap_or(0));
+        // `wfi` of guest traps so that hart can run others
+        trap_ctx.hstatus.set_vtw(self.guest_machine.wfi_trap);
+        // `sret`, `sfence.vma` and `satp` are emulated by hypervisor
+        trap_ctx.hstatus.set_vtsr(self.guest_machine.vm_trap);
+        trap_ctx.hstatus.set_vtvm(self.guest_machine.vm_trap);
+        if restore_csrs || reset {
+            vcpu.vs_csrs.restore();
+            vcpu.fp.restore(trap_ctx);
+            vcpu.vector.restore(trap_ctx);
+            unsafe{ hvip::write(vcpu.hvip) };
+        }
+        unsafe{ htimedelta::write(self.time_delta) };
+        self.vcpu_id = next;
+    }
+
+    /// save the context of running vcpu and load the context of `next`
+    pub fn switch_vcpu(&mut self, next: usize) {
+        let switch = next != self.vcpu_id;
+        if switch {
+            self.save_vcpu();
+        }
+        self.load_vcpu(next, switch);
+    }
+}
+
+
+/// `htimedelta` which makes guest time start from zero now
+fn boot_time_delta() -> usize {
+    0usize.wrapping_sub(time::read())
 }
 
 
@@ -64,8 +356,10 @@ pub mod page_table {
 }
 
 pub mod pmap {
+    use alloc::vec::Vec;
     use riscv_decode::Instruction;
 
+    use crate::constants::PAGE_SIZE;
     use crate::{mm::{MemorySet, GuestMemorySet}, page_table::translate_guest_va};
     use super::page_table::GuestPageTable;
     // use riscv_decode;
@@ -122,6 +416,21 @@ pub mod pmap {
     }
 
 
+    /// Split guest physical range `[guest_pa, guest_pa + len)` into host physical
+    /// chunks which do not cross page boundary, return None if any page is unmapped.
+    pub fn gpa_range_to_hpa<G: GuestPageTable>(gpm: &GuestMemorySet<G>, guest_pa: usize, len: usize) -> Option<Vec<(usize, usize)>> {
+        let mut chunks = Vec::new();
+        let mut addr = guest_pa;
+        let end = guest_pa.checked_add(len)?;
+        while addr < end {
+            let chunk_len = (PAGE_SIZE - (addr & (PAGE_SIZE - 1))).min(end - addr);
+            let host_pa = gpm.translate_va(addr)?;
+            chunks.push((host_pa, chunk_len));
+            addr += chunk_len;
+        }
+        Some(chunks)
+    }
+
     pub fn decode_inst_at_addr(host_va: usize) -> (usize, Option<Instruction>) {
         let i1 = unsafe{ core::ptr::read(host_va as *const u16) };
         let len = riscv_decode::instruction_length(i1);
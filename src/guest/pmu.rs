@@ -0,0 +1,293 @@
+//! Per guest PMU counters for SBI PMU extension.
+//!
+//! Counter `i` of guest is backed by host counter `i`, hardware counters are
+//! configured through firmware and saved/restored when guests switch, so
+//! every guest owns all hardware counters. Firmware counters follow hardware
+//! counters and count events seen by hypervisor.
+
+use arrayvec::ArrayVec;
+
+use crate::constants::csr::counter;
+use crate::sbi::{
+    pmu_counter_config_matching, pmu_counter_get_info, pmu_counter_start, pmu_counter_stop,
+    pmu_num_counters, SBI_ERR_ALREADY_STARTED, SBI_ERR_ALREADY_STOPPED, SBI_ERR_INAVLID_PARAM,
+    SBI_ERR_NOT_SUPPORTED, SBI_PMU_CFG_FLAG_AUTO_START, SBI_PMU_CFG_FLAG_CLEAR_VALUE,
+    SBI_PMU_CFG_FLAG_SKIP_MATCH, SBI_PMU_COUNTER_INFO_FIRMWARE, SBI_PMU_EVENT_TYPE_FIRMWARE,
+    SBI_PMU_FW_PLATFORM, SBI_PMU_START_FLAG_SET_INIT_VALUE, SBI_PMU_STOP_FLAG_RESET, SBI_SUCCESS,
+};
+
+pub const MAX_HW_COUNTERS: usize = 32;
+pub const NR_FW_COUNTERS: usize = 16;
+
+/// `event_data` of platform firmware events counted by hypervisor
+pub const PMU_PLATFORM_SBI_CALL: usize = 0;
+pub const PMU_PLATFORM_GUEST_PAGE_FAULT: usize = 1;
+
+#[derive(Clone, Copy, Default)]
+struct PmuCounter {
+    configured: bool,
+    started: bool,
+    event_idx: usize,
+    event_data: usize,
+    config_flags: usize,
+    /// saved value of hardware counter, or value of firmware counter
+    value: usize,
+}
+
+pub struct GuestPmu {
+    /// `counter_get_info` of host hardware counters
+    hw_info: ArrayVec<usize, MAX_HW_COUNTERS>,
+    counters: ArrayVec<PmuCounter, { MAX_HW_COUNTERS + NR_FW_COUNTERS }>,
+}
+
+impl GuestPmu {
+    pub fn new() -> Self {
+        let mut hw_info = ArrayVec::new();
+        for counter_idx in 0..pmu_num_counters().min(MAX_HW_COUNTERS) {
+            let (error, info) = pmu_counter_get_info(counter_idx);
+            // hardware counters come first
+            if error != SBI_SUCCESS || info & SBI_PMU_COUNTER_INFO_FIRMWARE != 0 {
+                break;
+            }
+            hw_info.push(info);
+        }
+        let mut counters = ArrayVec::new();
+        for _ in 0..hw_info.len() + NR_FW_COUNTERS {
+            counters.push(PmuCounter::default());
+        }
+        Self { hw_info, counters }
+    }
+
+    pub fn num_counters(&self) -> usize {
+        self.counters.len()
+    }
+
+    fn is_hw_counter(&self, counter_idx: usize) -> bool {
+        counter_idx < self.hw_info.len()
+    }
+
+    /// counter indices selected by `counter_idx_base` and `counter_idx_mask`
+    fn counter_indices(&self, base: usize, mask: usize) -> Result<ArrayVec<usize, { MAX_HW_COUNTERS + NR_FW_COUNTERS }>, isize> {
+        let mut indices = ArrayVec::new();
+        for i in (0..usize::BITS as usize).filter(|i| mask & (1 << i) != 0) {
+            match base.checked_add(i) {
+                Some(counter_idx) if counter_idx < self.num_counters() => indices.push(counter_idx),
+                _ => return Err(SBI_ERR_INAVLID_PARAM),
+            }
+        }
+        Ok(indices)
+    }
+
+    pub fn counter_info(&self, counter_idx: usize) -> Result<usize, isize> {
+        if self.is_hw_counter(counter_idx) {
+            Ok(self.hw_info[counter_idx])
+        } else if counter_idx < self.num_counters() {
+            Ok(SBI_PMU_COUNTER_INFO_FIRMWARE)
+        } else {
+            Err(SBI_ERR_INAVLID_PARAM)
+        }
+    }
+
+    pub fn config_matching(
+        &mut self,
+        base: usize,
+        mask: usize,
+        config_flags: usize,
+        event_idx: usize,
+        event_data: usize,
+    ) -> Result<usize, isize> {
+        let indices = self.counter_indices(base, mask)?;
+        let counter_idx = if event_idx >> 16 == SBI_PMU_EVENT_TYPE_FIRMWARE {
+            let counter_idx = indices
+                .iter()
+                .copied()
+                .filter(|&idx| !self.is_hw_counter(idx))
+                .find(|&idx| config_flags & SBI_PMU_CFG_FLAG_SKIP_MATCH != 0 || !self.counters[idx].configured)
+                .ok_or(SBI_ERR_NOT_SUPPORTED)?;
+            let counter = &mut self.counters[counter_idx];
+            if config_flags & SBI_PMU_CFG_FLAG_CLEAR_VALUE != 0 {
+                counter.value = 0;
+            }
+            counter_idx
+        } else {
+            // identity mapped, let firmware pick one of hardware counters
+            let hw_mask = indices
+                .iter()
+                .filter(|&&idx| self.is_hw_counter(idx))
+                .fold(0, |hw_mask, &idx| hw_mask | 1 << (idx - base));
+            if hw_mask == 0 {
+                return Err(SBI_ERR_NOT_SUPPORTED);
+            }
+            let (error, counter_idx) = pmu_counter_config_matching(base, hw_mask, config_flags, event_idx, event_data);
+            if error != SBI_SUCCESS {
+                return Err(error as isize);
+            }
+            counter_idx
+        };
+        let counter = &mut self.counters[counter_idx];
+        if config_flags & SBI_PMU_CFG_FLAG_SKIP_MATCH == 0 {
+            counter.event_idx = event_idx;
+            counter.event_data = event_data;
+            counter.config_flags = config_flags;
+        }
+        counter.configured = true;
+        counter.started = config_flags & SBI_PMU_CFG_FLAG_AUTO_START != 0;
+        Ok(counter_idx)
+    }
+
+    pub fn start(&mut self, base: usize, mask: usize, start_flags: usize, initial_value: usize) -> Result<(), isize> {
+        for counter_idx in self.counter_indices(base, mask)? {
+            let counter = &self.counters[counter_idx];
+            if !counter.configured {
+                return Err(SBI_ERR_INAVLID_PARAM);
+            }
+            if counter.started {
+                return Err(SBI_ERR_ALREADY_STARTED);
+            }
+            if self.is_hw_counter(counter_idx) {
+                // stopped counter goes on from its saved value unless guest sets one
+                let initial_value = if start_flags & SBI_PMU_START_FLAG_SET_INIT_VALUE != 0 {
+                    initial_value
+                } else {
+                    counter.value
+                };
+                let start_flags = start_flags | SBI_PMU_START_FLAG_SET_INIT_VALUE;
+                let error = pmu_counter_start(counter_idx, 1, start_flags, initial_value);
+                if error != SBI_SUCCESS {
+                    return Err(error as isize);
+                }
+            } else if start_flags & SBI_PMU_START_FLAG_SET_INIT_VALUE != 0 {
+                self.counters[counter_idx].value = initial_value;
+            }
+            self.counters[counter_idx].started = true;
+        }
+        Ok(())
+    }
+
+    pub fn stop(&mut self, base: usize, mask: usize, stop_flags: usize) -> Result<(), isize> {
+        for counter_idx in self.counter_indices(base, mask)? {
+            let counter = &self.counters[counter_idx];
+            if !counter.configured {
+                return Err(SBI_ERR_INAVLID_PARAM);
+            }
+            if !counter.started {
+                return Err(SBI_ERR_ALREADY_STOPPED);
+            }
+            let csr = self.hw_info.get(counter_idx).map(|&info| info & 0xfff);
+            if let Some(csr) = csr {
+                let error = pmu_counter_stop(counter_idx, 1, stop_flags);
+                if error != SBI_SUCCESS {
+                    return Err(error as isize);
+                }
+                // host counter may be released or taken by another guest later
+                self.counters[counter_idx].value = counter::read(csr).unwrap_or(0);
+            }
+            let counter = &mut self.counters[counter_idx];
+            counter.started = false;
+            if stop_flags & SBI_PMU_STOP_FLAG_RESET != 0 {
+                counter.configured = false;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn fw_read(&self, counter_idx: usize) -> Result<usize, isize> {
+        if self.is_hw_counter(counter_idx) || counter_idx >= self.num_counters() {
+            return Err(SBI_ERR_INAVLID_PARAM);
+        }
+        Ok(self.counters[counter_idx].value)
+    }
+
+    /// Value of hardware counter CSR `csr` read by guest, which is zero
+    /// unless guest configured the counter through SBI PMU.
+    pub fn read_hw_counter(&self, csr: usize) -> usize {
+        let counter_idx = match self.hw_info.iter().position(|&info| info & 0xfff == csr) {
+            Some(counter_idx) => counter_idx,
+            None => return 0,
+        };
+        let counter = &self.counters[counter_idx];
+        if counter.started {
+            counter::read(csr).unwrap_or(0)
+        } else if counter.configured {
+            counter.value
+        } else {
+            0
+        }
+    }
+
+    /// add `n` to started firmware counters monitoring event `code`,
+    /// platform events are also matched by `data`
+    pub fn count_fw_event(&mut self, code: usize, data: usize, n: usize) {
+        let fw_event_idx = SBI_PMU_EVENT_TYPE_FIRMWARE << 16 | code;
+        let nr_hw = self.hw_info.len();
+        for counter in self.counters[nr_hw..].iter_mut() {
+            if counter.started
+                && counter.event_idx == fw_event_idx
+                && (code != SBI_PMU_FW_PLATFORM || counter.event_data == data)
+            {
+                counter.value = counter.value.wrapping_add(n);
+            }
+        }
+    }
+
+    /// Save hardware counters and release them in firmware,
+    /// must be called before another guest runs on the hart.
+    pub fn save(&mut self) {
+        for counter_idx in 0..self.hw_info.len() {
+            let csr = self.hw_info[counter_idx] & 0xfff;
+            let counter = &mut self.counters[counter_idx];
+            if !counter.configured {
+                continue;
+            }
+            let error = if counter.started {
+                counter.value = counter::read(csr).unwrap_or(0);
+                pmu_counter_stop(counter_idx, 1, SBI_PMU_STOP_FLAG_RESET) as isize
+            } else {
+                // stopped counter is only released, its value was saved by `stop`
+                match pmu_counter_stop(counter_idx, 1, SBI_PMU_STOP_FLAG_RESET) as isize {
+                    SBI_ERR_ALREADY_STOPPED => SBI_SUCCESS as isize,
+                    error => error,
+                }
+            };
+            if error != SBI_SUCCESS as isize {
+                hwarning!("fail to release pmu counter {}: {}", counter_idx, error);
+            }
+        }
+    }
+
+    /// configure and start hardware counters saved by `save`
+    pub fn restore(&mut self) {
+        let config_mask = !(SBI_PMU_CFG_FLAG_SKIP_MATCH | SBI_PMU_CFG_FLAG_CLEAR_VALUE | SBI_PMU_CFG_FLAG_AUTO_START);
+        for counter_idx in 0..self.hw_info.len() {
+            let counter = &mut self.counters[counter_idx];
+            if !counter.configured {
+                continue;
+            }
+            let (error, _) = pmu_counter_config_matching(
+                counter_idx,
+                1,
+                counter.config_flags & config_mask,
+                counter.event_idx,
+                counter.event_data,
+            );
+            if error != SBI_SUCCESS {
+                hwarning!("fail to restore pmu counter {}: {}", counter_idx, error as isize);
+                counter.configured = false;
+                counter.started = false;
+                continue;
+            }
+            if counter.started {
+                pmu_counter_start(counter_idx, 1, SBI_PMU_START_FLAG_SET_INIT_VALUE, counter.value);
+            }
+        }
+    }
+
+    /// release hardware counters and clear all counters,
+    /// must be called when guest is running on the hart
+    pub fn reset(&mut self) {
+        self.save();
+        for counter in self.counters.iter_mut() {
+            *counter = PmuCounter::default();
+        }
+    }
+}
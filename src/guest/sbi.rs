@@ -1,19 +1,145 @@
+use super::page_table::GuestPageTable;
+use super::vcpu::{VCpuState, VCPU_EVENT_IPI};
 use super::vmexit::TrapContext;
+use super::Guest;
 use crate::constants::riscv_regs::GprIndex;
-use crate::sbi::leagcy::SBI_SET_TIMER;
+use crate::hypervisor::HostVmm;
+use crate::mm::MemorySet;
+use crate::page_table::{PageTable, PageTableSv39};
+use crate::sbi::leagcy::{
+    SBI_CLEAR_IPI, SBI_REMOTE_FENCE_I, SBI_REMOTE_SFENCE_VMA, SBI_REMOTE_SFENCE_VMA_ASID,
+    SBI_SEND_IPI, SBI_SET_TIMER, SBI_SHUTDOWN,
+};
 use crate::sbi::{
-    console_getchar, console_putchar, set_timer, SBI_CONSOLE_GETCHAR, SBI_CONSOLE_PUTCHAR,
-    SBI_ERR_NOT_SUPPORTED, SBI_EXTID_BASE, SBI_EXTID_TIME, SBI_GET_MARCHID_FID, SBI_GET_MIMPID_FID,
-    SBI_GET_MVENDORID_FID, SBI_GET_SBI_IMPL_ID_FID, SBI_GET_SBI_IMPL_VERSION_FID,
-    SBI_GET_SBI_SPEC_VERSION_FID, SBI_PROBE_EXTENSION_FID, SBI_SET_TIMER_FID, SBI_SUCCESS,
+    console_getchar, remote_fence_i,
+    set_timer, SBI_EXTID_RFNC, SBI_REMOTE_FENCE_I_FID, SBI_REMOTE_SFENCE_VMA_ASID_FID,
+    SBI_REMOTE_SFENCE_VMA_FID, SBI_CONSOLE_GETCHAR, SBI_CONSOLE_PUTCHAR,
+    SBI_ERR_ALREADY_AVAILABLE, SBI_ERR_FAILUER, SBI_ERR_INAVLID_PARAM, SBI_ERR_INVALID_ADDRESS,
+    SBI_ERR_NOT_SUPPORTED, SBI_EXTID_BASE, SBI_EXTID_HSM, SBI_EXTID_IPI, SBI_EXTID_TIME, SBI_GET_MARCHID_FID,
+    SBI_GET_MIMPID_FID, SBI_GET_MVENDORID_FID, SBI_GET_SBI_IMPL_ID_FID,
+    SBI_GET_SBI_IMPL_VERSION_FID, SBI_GET_SBI_SPEC_VERSION_FID, SBI_HART_START_FID,
+    SBI_HART_STATUS_FID, SBI_HART_STOP_FID, SBI_HART_SUSPEND_FID, SBI_HSM_SUSPEND_NON_RETENTIVE,
+    SBI_HSM_SUSPEND_RETENTIVE, SBI_PROBE_EXTENSION_FID, SBI_SEND_IPI_FID, SBI_SET_TIMER_FID,
+    SBI_SUCCESS, SBI_EXTID_SRST, SBI_RESET_TYPE_COLD_REBOOT, SBI_RESET_TYPE_SHUTDOWN,
+    SBI_RESET_TYPE_WARM_REBOOT, SBI_SYSTEM_RESET_FID, SBI_DBCN_CONSOLE_READ_FID,
+    SBI_DBCN_CONSOLE_WRITE_BYTE_FID, SBI_DBCN_CONSOLE_WRITE_FID, SBI_EXTID_DBCN,
+    SBI_EXTID_PMU, SBI_PMU_NUM_COUNTERS_FID, SBI_PMU_COUNTER_GET_INFO_FID,
+    SBI_PMU_COUNTER_CFG_MATCH_FID, SBI_PMU_COUNTER_START_FID, SBI_PMU_COUNTER_STOP_FID,
+    SBI_PMU_COUNTER_FW_READ_FID, SBI_PMU_FW_PLATFORM, SBI_PMU_FW_SET_TIMER, SBI_PMU_FW_IPI_SENT,
+    SBI_PMU_FW_IPI_RECEIVED, SBI_PMU_FW_FENCE_I_SENT, SBI_PMU_FW_SFENCE_VMA_SENT,
+    SBI_PMU_FW_SFENCE_VMA_ASID_SENT, SBI_EXTID_STA, SBI_STA_SET_SHMEM_FID,
 };
+use super::pmu::PMU_PLATFORM_SBI_CALL;
 use crate::VmmResult;
+use crate::constants::PAGE_SIZE;
+use crate::constants::csr::vstimecmp;
+use super::pmap::{gpa_range_to_hpa, two_stage_translation};
+use core::arch::riscv64::{hfence_vvma, hfence_vvma_all, hfence_vvma_asid, hfence_vvma_vaddr};
 use sbi_rt;
 
-use riscv::register::{hvip, sie};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use riscv::register::{hvip, sie, vsatp};
+/// flush the entire address space instead of page by page above this size
+const HFENCE_VVMA_MAX_PAGES: usize = 64;
+
 pub struct SbiRet {
-    error: usize,
-    value: usize,
+    pub error: usize,
+    pub value: usize,
+}
+
+impl SbiRet {
+    pub fn success(value: usize) -> Self {
+        Self { error: SBI_SUCCESS, value }
+    }
+
+    pub fn error(error: isize) -> Self {
+        Self { error: error as usize, value: 0 }
+    }
+}
+
+/// An SBI extension emulated for guests. Extensions are registered into
+/// `SbiRegistry` of each guest, so vendor extensions can be plugged in
+/// without touching `sbi_vs_handler`.
+pub trait SbiExtension<G: GuestPageTable>: Send + Sync {
+    /// extension IDs served, legacy extensions use one ID per function
+    fn extension_ids(&self) -> &[usize];
+
+    /// handle call `fid` of extension in `a7` from current vcpu,
+    /// `a0` and `a1` of `ctx` are set from return value by caller
+    fn handle(
+        &self,
+        host_vmm: &mut HostVmm<PageTableSv39, G>,
+        fid: usize,
+        ctx: &mut TrapContext,
+    ) -> SbiRet;
+
+    /// value returned by `probe_extension`, 0 means unavailable
+    fn probe(&self, _ext_id: usize) -> usize {
+        1
+    }
+}
+
+/// SBI extensions visible to a guest, `probe_extension` is answered from it
+/// instead of the firmware, since firmware knows nothing about guest.
+pub struct SbiRegistry<G: GuestPageTable> {
+    extensions: Vec<Arc<dyn SbiExtension<G>>>,
+}
+
+impl<G: GuestPageTable> SbiRegistry<G> {
+    pub fn new() -> Self {
+        Self { extensions: Vec::new() }
+    }
+
+    /// Register extensions emulated by hypervisor according to guest VM config,
+    /// extensions off by default must be turned on by `enable`.
+    /// Base extension is mandatory.
+    pub fn with_config(enable: &[usize], disable: &[usize]) -> Self {
+        let mut registry = Self::new();
+        registry.register(Arc::new(BaseExtension));
+        let emulated: [(Arc<dyn SbiExtension<G>>, bool); 9] = [
+            (Arc::new(TimeExtension), true),
+            (Arc::new(IpiExtension), true),
+            (Arc::new(RfenceExtension), true),
+            (Arc::new(HsmExtension), true),
+            (Arc::new(SrstExtension), true),
+            (Arc::new(DbcnExtension), true),
+            (Arc::new(LegacyExtension), true),
+            (Arc::new(PmuExtension), true),
+            (Arc::new(StaExtension), true),
+        ];
+        for (extension, default) in emulated {
+            let ext_ids = extension.extension_ids();
+            let on = default || ext_ids.iter().any(|ext_id| enable.contains(ext_id));
+            if on && ext_ids.iter().all(|ext_id| !disable.contains(ext_id)) {
+                registry.register(extension);
+            }
+        }
+        for ext_id in enable.iter().filter(|&&ext_id| registry.find(ext_id).is_none()) {
+            hwarning!("SBI extension {:#x} is not emulated, ignore", ext_id);
+        }
+        registry
+    }
+
+    /// register an extension, replacing extensions serving the same IDs
+    pub fn register(&mut self, extension: Arc<dyn SbiExtension<G>>) {
+        let ext_ids = extension.extension_ids();
+        self.extensions.retain(|registered| {
+            registered.extension_ids().iter().all(|ext_id| !ext_ids.contains(ext_id))
+        });
+        self.extensions.push(extension);
+    }
+
+    pub fn find(&self, ext_id: usize) -> Option<Arc<dyn SbiExtension<G>>> {
+        self.extensions
+            .iter()
+            .find(|extension| extension.extension_ids().contains(&ext_id))
+            .cloned()
+    }
+
+    pub fn probe(&self, ext_id: usize) -> usize {
+        self.find(ext_id).map_or(0, |extension| extension.probe(ext_id))
+    }
 }
 
 #[inline(always)]
@@ -31,26 +157,106 @@ pub(crate) fn sbi_call_1(eid: usize, fid: usize, arg0: usize) -> SbiRet {
     SbiRet { error, value }
 }
 
-pub fn sbi_vs_handler(ctx: &mut TrapContext) -> VmmResult {
+pub fn sbi_vs_handler<G: GuestPageTable>(
+    host_vmm: &mut HostVmm<PageTableSv39, G>,
+    ctx: &mut TrapContext,
+) -> VmmResult {
     let ext_id: usize = ctx.x[GprIndex::A7 as usize];
     let fid: usize = ctx.x[GprIndex::A6 as usize];
-    let sbi_ret;
 
-    match ext_id {
-        SBI_EXTID_BASE => sbi_ret = sbi_base_handler(fid, ctx),
-        SBI_EXTID_TIME => sbi_ret = sbi_time_handler(ctx.x[GprIndex::A0 as usize], fid),
-        SBI_CONSOLE_PUTCHAR => sbi_ret = sbi_console_putchar_handler(ctx.x[GprIndex::A0 as usize]),
-        SBI_CONSOLE_GETCHAR => sbi_ret = sbi_console_getchar_handler(),
-        SBI_SET_TIMER => sbi_ret = sbi_legacy_set_time(ctx.x[GprIndex::A0 as usize]),
-        _ => panic!("Unsupported SBI call id {:#x}", ext_id),
-    }
+    let guest = host_vmm.guests[host_vmm.guest_id].as_mut().unwrap();
+    guest.pmu.count_fw_event(SBI_PMU_FW_PLATFORM, PMU_PLATFORM_SBI_CALL, 1);
+    let sbi_ret = match guest.sbi.find(ext_id) {
+        Some(extension) => extension.handle(host_vmm, fid, ctx),
+        None => {
+            hwarning!("Unsupported SBI call id {:#x}, fid {:#x}", ext_id, fid);
+            SbiRet::error(SBI_ERR_NOT_SUPPORTED)
+        }
+    };
     ctx.x[GprIndex::A0 as usize] = sbi_ret.error;
     ctx.x[GprIndex::A1 as usize] = sbi_ret.value;
 
     Ok(())
 }
 
-pub fn sbi_base_handler(fid: usize, ctx: &TrapContext) -> SbiRet {
+/// define an extension emulated by hypervisor which forwards calls to handler
+macro_rules! sbi_extension {
+    ($name:ident, [$($ext_id:expr),+], |$host_vmm:ident, $fid:ident, $ctx:ident| $handle:expr) => {
+        pub struct $name;
+
+        impl<G: GuestPageTable> SbiExtension<G> for $name {
+            fn extension_ids(&self) -> &[usize] {
+                &[$($ext_id),+]
+            }
+
+            fn handle(
+                &self,
+                $host_vmm: &mut HostVmm<PageTableSv39, G>,
+                $fid: usize,
+                $ctx: &mut TrapContext,
+            ) -> SbiRet {
+                $handle
+            }
+        }
+    };
+}
+
+sbi_extension!(BaseExtension, [SBI_EXTID_BASE], |host_vmm, fid, ctx| {
+    let guest = host_vmm.guests[host_vmm.guest_id].as_ref().unwrap();
+    sbi_base_handler(&guest.sbi, fid, ctx)
+});
+
+sbi_extension!(TimeExtension, [SBI_EXTID_TIME], |host_vmm, fid, ctx| {
+    let guest = host_vmm.guests[host_vmm.guest_id].as_mut().unwrap();
+    guest.pmu.count_fw_event(SBI_PMU_FW_SET_TIMER, 0, 1);
+    sbi_time_handler(host_vmm, ctx.x[GprIndex::A0 as usize], fid)
+});
+
+sbi_extension!(HsmExtension, [SBI_EXTID_HSM], |host_vmm, fid, ctx| {
+    let guest = host_vmm.guests[host_vmm.guest_id].as_mut().unwrap();
+    sbi_hsm_handler(guest, fid, ctx)
+});
+
+sbi_extension!(IpiExtension, [SBI_EXTID_IPI], |host_vmm, fid, ctx| {
+    let guest = host_vmm.guests[host_vmm.guest_id].as_mut().unwrap();
+    sbi_ipi_handler(guest, fid, ctx)
+});
+
+sbi_extension!(RfenceExtension, [SBI_EXTID_RFNC], |host_vmm, fid, ctx| {
+    let hart_id = host_vmm.hart_id;
+    let guest = host_vmm.guests[host_vmm.guest_id].as_mut().unwrap();
+    sbi_rfence_handler(guest, hart_id, fid, ctx)
+});
+
+sbi_extension!(SrstExtension, [SBI_EXTID_SRST], |host_vmm, fid, ctx| {
+    sbi_srst_handler(host_vmm, fid, ctx)
+});
+
+sbi_extension!(DbcnExtension, [SBI_EXTID_DBCN], |host_vmm, fid, ctx| {
+    sbi_dbcn_handler(host_vmm, fid, ctx)
+});
+
+sbi_extension!(PmuExtension, [SBI_EXTID_PMU], |host_vmm, fid, ctx| {
+    let guest = host_vmm.guests[host_vmm.guest_id].as_mut().unwrap();
+    sbi_pmu_handler(guest, fid, ctx)
+});
+
+sbi_extension!(StaExtension, [SBI_EXTID_STA], |host_vmm, fid, ctx| {
+    let guest = host_vmm.guests[host_vmm.guest_id].as_mut().unwrap();
+    sbi_sta_handler(guest, fid, ctx)
+});
+
+// legacy extensions have no function id
+sbi_extension!(
+    LegacyExtension,
+    [
+        SBI_SET_TIMER, SBI_CONSOLE_PUTCHAR, SBI_CONSOLE_GETCHAR, SBI_CLEAR_IPI, SBI_SEND_IPI,
+        SBI_REMOTE_FENCE_I, SBI_REMOTE_SFENCE_VMA, SBI_REMOTE_SFENCE_VMA_ASID, SBI_SHUTDOWN
+    ],
+    |host_vmm, _fid, ctx| sbi_legacy_handler(host_vmm, ctx)
+);
+
+pub fn sbi_base_handler<G: GuestPageTable>(sbi: &SbiRegistry<G>, fid: usize, ctx: &TrapContext) -> SbiRet {
     let mut sbi_ret = SbiRet {
         error: SBI_SUCCESS,
         value: 0,
@@ -70,8 +276,8 @@ pub fn sbi_base_handler(fid: usize, ctx: &TrapContext) -> SbiRet {
         }
         SBI_PROBE_EXTENSION_FID => {
             let extension = ctx.x[GprIndex::A0 as usize];
-            sbi_ret = sbi_call_1(SBI_EXTID_BASE, fid, extension);
-            htracking!("ProbeExtension: {}", sbi_ret.value);
+            sbi_ret.value = sbi.probe(extension);
+            htracking!("ProbeExtension: {:#x} -> {}", extension, sbi_ret.value);
         }
         SBI_GET_MVENDORID_FID => {
             sbi_ret.value = sbi_rt::get_mvendorid();
@@ -85,13 +291,51 @@ pub fn sbi_base_handler(fid: usize, ctx: &TrapContext) -> SbiRet {
             sbi_ret.value = sbi_rt::get_mimpid();
             htracking!("GetMimpId: {}", sbi_ret.value);
         }
-        _ => panic!("sbi base handler fid: {}", fid),
+        _ => {
+            hwarning!("Unsupported SBI base fid {:#x}", fid);
+            sbi_ret = SbiRet::error(SBI_ERR_NOT_SUPPORTED);
+        }
     }
     sbi_ret
 }
 
-pub fn sbi_console_putchar_handler(c: usize) -> SbiRet {
-    console_putchar(c);
+/// System reset only stops or reboots the calling guest
+pub fn sbi_srst_handler<P: PageTable, G: GuestPageTable>(
+    host_vmm: &mut HostVmm<P, G>,
+    fid: usize,
+    ctx: &TrapContext,
+) -> SbiRet {
+    let mut sbi_ret = SbiRet {
+        error: SBI_SUCCESS,
+        value: 0,
+    };
+    if fid != SBI_SYSTEM_RESET_FID {
+        sbi_ret.error = SBI_ERR_NOT_SUPPORTED as usize;
+        return sbi_ret;
+    }
+    // a0: reset_type, a1: reset_reason
+    let reset_type = ctx.x[GprIndex::A0 as usize];
+    let reset_reason = ctx.x[GprIndex::A1 as usize];
+    let guest_id = host_vmm.guest_id;
+    match reset_type {
+        SBI_RESET_TYPE_SHUTDOWN => {
+            htracking!("SystemReset: guest {} shutdown, reason {}", guest_id, reset_reason);
+            host_vmm.shutdown_guest(guest_id);
+        }
+        SBI_RESET_TYPE_COLD_REBOOT | SBI_RESET_TYPE_WARM_REBOOT => {
+            htracking!("SystemReset: guest {} reboot, reason {}", guest_id, reset_reason);
+            host_vmm.reboot_guest(guest_id);
+        }
+        _ => sbi_ret.error = SBI_ERR_INAVLID_PARAM as usize,
+    }
+    sbi_ret
+}
+
+pub fn sbi_console_putchar_handler<P: PageTable, G: GuestPageTable>(
+    host_vmm: &mut HostVmm<P, G>,
+    c: usize,
+) -> SbiRet {
+    host_vmm.guest_console_putchar(c as u8);
     return SbiRet {
         error: SBI_SUCCESS,
         value: 0,
@@ -106,7 +350,70 @@ pub fn sbi_console_getchar_handler() -> SbiRet {
     };
 }
 
-pub fn sbi_time_handler(stime: usize, fid: usize) -> SbiRet {
+/// Debug console, buffer addresses are guest physical addresses
+pub fn sbi_dbcn_handler<P: PageTable, G: GuestPageTable>(
+    host_vmm: &mut HostVmm<P, G>,
+    fid: usize,
+    ctx: &TrapContext,
+) -> SbiRet {
+    let mut sbi_ret = SbiRet {
+        error: SBI_SUCCESS,
+        value: 0,
+    };
+    // a0: num_bytes(byte for write_byte), a1: base_addr_lo, a2: base_addr_hi
+    let num_bytes = ctx.x[GprIndex::A0 as usize];
+    let base_addr = ctx.x[GprIndex::A1 as usize];
+    let base_addr_hi = ctx.x[GprIndex::A2 as usize];
+    if fid == SBI_DBCN_CONSOLE_WRITE_BYTE_FID {
+        host_vmm.guest_console_putchar(num_bytes as u8);
+        return sbi_ret;
+    }
+    if fid != SBI_DBCN_CONSOLE_WRITE_FID && fid != SBI_DBCN_CONSOLE_READ_FID {
+        sbi_ret.error = SBI_ERR_NOT_SUPPORTED as usize;
+        return sbi_ret;
+    }
+    // physical address wider than XLEN is not supported
+    let chunks = match host_vmm.guests[host_vmm.guest_id].as_ref() {
+        Some(guest) if base_addr_hi == 0 => gpa_range_to_hpa(&guest.gpm, base_addr, num_bytes),
+        _ => None,
+    };
+    let chunks = match chunks {
+        Some(chunks) => chunks,
+        None => {
+            sbi_ret.error = SBI_ERR_INAVLID_PARAM as usize;
+            return sbi_ret;
+        }
+    };
+    if fid == SBI_DBCN_CONSOLE_WRITE_FID {
+        for (host_pa, len) in chunks {
+            let bytes = unsafe { core::slice::from_raw_parts(host_pa as *const u8, len) };
+            for &c in bytes {
+                host_vmm.guest_console_putchar(c);
+            }
+            sbi_ret.value += len;
+        }
+    } else {
+        'read: for (host_pa, len) in chunks {
+            let bytes = unsafe { core::slice::from_raw_parts_mut(host_pa as *mut u8, len) };
+            for byte in bytes.iter_mut() {
+                // legacy getchar returns -1 when no input is available
+                let c = console_getchar();
+                if c == usize::MAX {
+                    break 'read;
+                }
+                *byte = c as u8;
+                sbi_ret.value += 1;
+            }
+        }
+    }
+    sbi_ret
+}
+
+pub fn sbi_time_handler<P: PageTable, G: GuestPageTable>(
+    host_vmm: &mut HostVmm<P, G>,
+    stime: usize,
+    fid: usize,
+) -> SbiRet {
     let mut sbi_ret = SbiRet {
         error: SBI_SUCCESS,
         value: 0,
@@ -117,31 +424,417 @@ pub fn sbi_time_handler(stime: usize, fid: usize) -> SbiRet {
     }
 
     // htracking!("set timer: {}", stime);
-    set_timer(stime);
-    unsafe {
-        // clear guest timer interrupt pending
-        hvip::clear_vstip();
-        // enable timer interrupt
-        sie::set_stimer();
-    }
+    set_guest_timer(host_vmm, stime);
     return sbi_ret;
 }
 
-// pub fn sbi_rfence_handler(fid: usize) {
+/// program next timer event of running vcpu
+fn set_guest_timer<P: PageTable, G: GuestPageTable>(host_vmm: &mut HostVmm<P, G>, stime: usize) {
+    if host_vmm.sstc {
+        // `VSTIP` follows `vstimecmp`, which is compared with guest time,
+        // no host timer interrupt needed
+        unsafe {
+            vstimecmp::write(stime);
+            hvip::clear_vstip();
+        }
+        return;
+    }
+    // host timer is shared by all vcpus through timer queue,
+    // deadline is in guest time
+    let guest = host_vmm.guests[host_vmm.guest_id].as_ref().unwrap();
+    let deadline = guest.guest_time_to_host(stime);
+    host_vmm.set_vcpu_timer(deadline);
+}
 
-// }
+pub fn sbi_hsm_handler<G: GuestPageTable>(
+    guest: &mut Guest<G>,
+    fid: usize,
+    ctx: &TrapContext,
+) -> SbiRet {
+    let mut sbi_ret = SbiRet {
+        error: SBI_SUCCESS,
+        value: 0,
+    };
+    let a0 = ctx.x[GprIndex::A0 as usize];
+    let a1 = ctx.x[GprIndex::A1 as usize];
+    let a2 = ctx.x[GprIndex::A2 as usize];
+    match fid {
+        SBI_HART_START_FID => {
+            // a0: hartid, a1: start_addr, a2: opaque
+            let Some(vcpu) = guest.vcpus.get(a0) else {
+                sbi_ret.error = SBI_ERR_INAVLID_PARAM as usize;
+                return sbi_ret;
+            };
+            if vcpu.state != VCpuState::Stopped {
+                sbi_ret.error = SBI_ERR_ALREADY_AVAILABLE as usize;
+                return sbi_ret;
+            }
+            if !guest.is_executable(a1) {
+                sbi_ret.error = SBI_ERR_INVALID_ADDRESS as usize;
+                return sbi_ret;
+            }
+            let vcpu = &mut guest.vcpus[a0];
+            vcpu.start_addr = a1;
+            vcpu.opaque = a2;
+            vcpu.non_retentive = false;
+            vcpu.state = VCpuState::StartPending;
+            htracking!("HartStart: hart {}, start addr {:#x}", a0, a1);
+        }
+        SBI_HART_STOP_FID => {
+            let vcpu = &mut guest.vcpus[guest.vcpu_id];
+            if vcpu.state != VCpuState::Started {
+                sbi_ret.error = SBI_ERR_FAILUER as usize;
+                return sbi_ret;
+            }
+            // vcpu will be switched out when trap handler returns
+            vcpu.state = VCpuState::Stopped;
+            htracking!("HartStop: hart {}", vcpu.hart);
+        }
+        SBI_HART_STATUS_FID => {
+            // a0: hartid
+            if let Some(vcpu) = guest.vcpus.get(a0) {
+                sbi_ret.value = vcpu.state as usize;
+            } else {
+                sbi_ret.error = SBI_ERR_INAVLID_PARAM as usize;
+            }
+        }
+        SBI_HART_SUSPEND_FID => {
+            // a0: suspend_type, a1: resume_addr, a2: opaque
+            let non_retentive = match a0 {
+                SBI_HSM_SUSPEND_RETENTIVE => false,
+                SBI_HSM_SUSPEND_NON_RETENTIVE => true,
+                0x1000_0000..=0x7fff_ffff | 0x9000_0000..=0xffff_ffff => {
+                    // platform specific suspend types
+                    sbi_ret.error = SBI_ERR_NOT_SUPPORTED as usize;
+                    return sbi_ret;
+                }
+                _ => {
+                    sbi_ret.error = SBI_ERR_INAVLID_PARAM as usize;
+                    return sbi_ret;
+                }
+            };
+            if non_retentive && !guest.is_executable(a1) {
+                sbi_ret.error = SBI_ERR_INVALID_ADDRESS as usize;
+                return sbi_ret;
+            }
+            let vcpu = &mut guest.vcpus[guest.vcpu_id];
+            vcpu.non_retentive = non_retentive;
+            if non_retentive {
+                vcpu.start_addr = a1;
+                vcpu.opaque = a2;
+            }
+            vcpu.state = VCpuState::Suspended;
+        }
+        _ => sbi_ret.error = SBI_ERR_NOT_SUPPORTED as usize,
+    }
+    sbi_ret
+}
 
-pub fn sbi_legacy_set_time(stime: usize) -> SbiRet {
-    let sbi_ret = SbiRet {
+/// bitmap of all `nr_vcpus` vcpus of guest, without overflow at `usize::BITS`
+fn all_vcpus_mask(nr_vcpus: usize) -> usize {
+    usize::MAX
+        .checked_shr(usize::BITS - nr_vcpus as u32)
+        .unwrap_or(0)
+}
+
+/// decode `hart_mask` and `hart_mask_base` into a bitmap of guest vcpu ids,
+/// `hart_mask_base` equal to `usize::MAX` means all vcpus
+pub fn decode_hart_mask(hart_mask: usize, hart_mask_base: usize, nr_vcpus: usize) -> Result<usize, isize> {
+    if hart_mask_base == usize::MAX {
+        return Ok(all_vcpus_mask(nr_vcpus));
+    }
+    if hart_mask_base >= nr_vcpus {
+        return Err(SBI_ERR_INAVLID_PARAM);
+    }
+    let mut vcpu_mask = 0;
+    for i in 0..usize::BITS as usize {
+        if hart_mask & (1 << i) == 0 {
+            continue;
+        }
+        match hart_mask_base.checked_add(i) {
+            Some(hart) if hart < nr_vcpus => vcpu_mask |= 1 << hart,
+            _ => return Err(SBI_ERR_INAVLID_PARAM),
+        }
+    }
+    Ok(vcpu_mask)
+}
+
+pub fn sbi_ipi_handler<G: GuestPageTable>(
+    guest: &mut Guest<G>,
+    fid: usize,
+    ctx: &TrapContext,
+) -> SbiRet {
+    let mut sbi_ret = SbiRet {
         error: SBI_SUCCESS,
         value: 0,
     };
-    set_timer(stime);
+    if fid != SBI_SEND_IPI_FID {
+        sbi_ret.error = SBI_ERR_NOT_SUPPORTED as usize;
+        return sbi_ret;
+    }
+    // a0: hart_mask, a1: hart_mask_base
+    let hart_mask = ctx.x[GprIndex::A0 as usize];
+    let hart_mask_base = ctx.x[GprIndex::A1 as usize];
+    match decode_hart_mask(hart_mask, hart_mask_base, guest.vcpus.len()) {
+        Ok(vcpu_mask) => guest_send_ipi(guest, vcpu_mask),
+        Err(err) => sbi_ret.error = err as usize,
+    }
+    sbi_ret
+}
+
+fn guest_send_ipi<G: GuestPageTable>(guest: &mut Guest<G>, vcpu_mask: usize) {
+    let nr_ipis = vcpu_mask.count_ones() as usize;
+    guest.pmu.count_fw_event(SBI_PMU_FW_IPI_SENT, 0, nr_ipis);
+    guest.pmu.count_fw_event(SBI_PMU_FW_IPI_RECEIVED, 0, nr_ipis);
+    guest.send_ipi(vcpu_mask)
+}
+
+/// Flush guest TLB entries of the VMID in current `hgatp`. `start_addr` and `size`
+/// both equal to 0 or `size` equal to `usize::MAX` means flushing the entire address space.
+fn hfence_vvma_range(start_addr: usize, size: usize, asid: Option<usize>) {
+    if size == usize::MAX {
+        unsafe { hfence_vvma_all() };
+        return;
+    }
+    // flush every page touched by the range, including partial ones at both ends
+    let start = start_addr & !(PAGE_SIZE - 1);
+    let end = start_addr
+        .saturating_add(size)
+        .saturating_add(PAGE_SIZE - 1)
+        & !(PAGE_SIZE - 1);
+    let full_flush = (start_addr == 0 && size == 0) || end - start > HFENCE_VVMA_MAX_PAGES * PAGE_SIZE;
     unsafe {
-        // clear guest timer interrupt pending
-        hvip::clear_vstip();
-        // enable timer interrupt
-        sie::set_stimer();
+        match (full_flush, asid) {
+            (true, None) => hfence_vvma_all(),
+            (true, Some(asid)) => hfence_vvma_asid(asid),
+            (false, None) => {
+                for addr in (start..end).step_by(PAGE_SIZE) {
+                    hfence_vvma_vaddr(addr);
+                }
+            }
+            (false, Some(asid)) => {
+                for addr in (start..end).step_by(PAGE_SIZE) {
+                    hfence_vvma(addr, asid);
+                }
+            }
+        }
+    }
+}
+
+pub fn sbi_rfence_handler<G: GuestPageTable>(
+    guest: &mut Guest<G>,
+    hart_id: usize,
+    fid: usize,
+    ctx: &TrapContext,
+) -> SbiRet {
+    // a0: hart_mask, a1: hart_mask_base, a2: start_addr, a3: size, a4: asid
+    let hart_mask = ctx.x[GprIndex::A0 as usize];
+    let hart_mask_base = ctx.x[GprIndex::A1 as usize];
+    let start_addr = ctx.x[GprIndex::A2 as usize];
+    let size = ctx.x[GprIndex::A3 as usize];
+    let asid = ctx.x[GprIndex::A4 as usize];
+    match decode_hart_mask(hart_mask, hart_mask_base, guest.vcpus.len()) {
+        Ok(vcpu_mask) => guest_remote_fence(guest, hart_id, fid, vcpu_mask, start_addr, size, asid),
+        Err(err) => SbiRet::error(err),
+    }
+}
+
+/// remote fence `fid` of RFENCE extension on vcpus in `vcpu_mask`
+fn guest_remote_fence<G: GuestPageTable>(
+    guest: &mut Guest<G>,
+    hart_id: usize,
+    fid: usize,
+    vcpu_mask: usize,
+    start_addr: usize,
+    size: usize,
+    asid: usize,
+) -> SbiRet {
+    let mut sbi_ret = SbiRet {
+        error: SBI_SUCCESS,
+        value: 0,
+    };
+    // Vcpus of the same guest share the VMID, which is only fenced locally.
+    // Firmware would fence remote harts for the VMID in their own `hgatp`,
+    // so vcpus placed on other harts flush their TLB on next entry instead.
+    let nr_fences = vcpu_mask.count_ones() as usize;
+    match fid {
+        SBI_REMOTE_FENCE_I_FID => guest.pmu.count_fw_event(SBI_PMU_FW_FENCE_I_SENT, 0, nr_fences),
+        SBI_REMOTE_SFENCE_VMA_FID => guest.pmu.count_fw_event(SBI_PMU_FW_SFENCE_VMA_SENT, 0, nr_fences),
+        SBI_REMOTE_SFENCE_VMA_ASID_FID => guest.pmu.count_fw_event(SBI_PMU_FW_SFENCE_VMA_ASID_SENT, 0, nr_fences),
+        _ => {}
+    }
+    let phys_mask = guest.phys_hart_mask(vcpu_mask);
+    let local = phys_mask & (1 << hart_id) != 0;
+    let remote_mask = phys_mask & !(1 << hart_id);
+    match fid {
+        SBI_REMOTE_FENCE_I_FID => {
+            if local {
+                unsafe { core::arch::asm!("fence.i") };
+            }
+            if remote_mask != 0 {
+                sbi_ret.error = remote_fence_i(remote_mask, 0);
+            }
+        }
+        SBI_REMOTE_SFENCE_VMA_FID => {
+            if local {
+                hfence_vvma_range(start_addr, size, None);
+            }
+            guest.defer_tlb_flush(vcpu_mask, hart_id);
+        }
+        SBI_REMOTE_SFENCE_VMA_ASID_FID => {
+            if local {
+                hfence_vvma_range(start_addr, size, Some(asid));
+            }
+            guest.defer_tlb_flush(vcpu_mask, hart_id);
+        }
+        // guest is not a hypervisor, hfence calls are not supported
+        _ => sbi_ret.error = SBI_ERR_NOT_SUPPORTED as usize,
+    }
+    sbi_ret
+}
+
+pub fn sbi_pmu_handler<G: GuestPageTable>(
+    guest: &mut Guest<G>,
+    fid: usize,
+    ctx: &TrapContext,
+) -> SbiRet {
+    let a0 = ctx.x[GprIndex::A0 as usize];
+    let a1 = ctx.x[GprIndex::A1 as usize];
+    let a2 = ctx.x[GprIndex::A2 as usize];
+    let a3 = ctx.x[GprIndex::A3 as usize];
+    let a4 = ctx.x[GprIndex::A4 as usize];
+    let pmu = &mut guest.pmu;
+    let ret = match fid {
+        SBI_PMU_NUM_COUNTERS_FID => Ok(pmu.num_counters()),
+        // a0: counter_idx
+        SBI_PMU_COUNTER_GET_INFO_FID => pmu.counter_info(a0),
+        // a0: counter_idx_base, a1: counter_idx_mask, a2: config_flags, a3: event_idx, a4: event_data
+        SBI_PMU_COUNTER_CFG_MATCH_FID => pmu.config_matching(a0, a1, a2, a3, a4),
+        // a0: counter_idx_base, a1: counter_idx_mask, a2: start_flags, a3: initial_value
+        SBI_PMU_COUNTER_START_FID => pmu.start(a0, a1, a2, a3).map(|_| 0),
+        // a0: counter_idx_base, a1: counter_idx_mask, a2: stop_flags
+        SBI_PMU_COUNTER_STOP_FID => pmu.stop(a0, a1, a2).map(|_| 0),
+        // a0: counter_idx
+        SBI_PMU_COUNTER_FW_READ_FID => pmu.fw_read(a0),
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    };
+    match ret {
+        Ok(value) => SbiRet::success(value),
+        Err(error) => SbiRet::error(error),
+    }
+}
+
+/// Steal time shared memory of calling vcpu, address is guest physical address
+pub fn sbi_sta_handler<G: GuestPageTable>(
+    guest: &mut Guest<G>,
+    fid: usize,
+    ctx: &TrapContext,
+) -> SbiRet {
+    if fid != SBI_STA_SET_SHMEM_FID {
+        return SbiRet::error(SBI_ERR_NOT_SUPPORTED);
+    }
+    // a0: shmem_phys_lo, a1: shmem_phys_hi, a2: flags
+    let shmem_lo = ctx.x[GprIndex::A0 as usize];
+    let shmem_hi = ctx.x[GprIndex::A1 as usize];
+    let flags = ctx.x[GprIndex::A2 as usize];
+    if flags != 0 {
+        return SbiRet::error(SBI_ERR_INAVLID_PARAM);
+    }
+    let vcpu_id = guest.vcpu_id;
+    if shmem_lo == usize::MAX && shmem_hi == usize::MAX {
+        guest.vcpus[vcpu_id].sta.disable();
+        return SbiRet::success(0);
+    }
+    if shmem_lo % 64 != 0 {
+        return SbiRet::error(SBI_ERR_INAVLID_PARAM);
     }
+    // physical address wider than XLEN is not supported
+    match guest.gpm.translate_va(shmem_lo) {
+        Some(host_pa) if shmem_hi == 0 => {
+            guest.vcpus[vcpu_id].sta.set_shmem(host_pa);
+            SbiRet::success(0)
+        }
+        _ => SbiRet::error(SBI_ERR_INVALID_ADDRESS),
+    }
+}
+
+/// read hart mask of legacy calls, which is passed by guest virtual address,
+/// NULL means all harts
+fn read_legacy_hart_mask<G: GuestPageTable>(guest: &Guest<G>, hart_mask_addr: usize) -> Option<usize> {
+    let all_harts = all_vcpus_mask(guest.vcpus.len());
+    if hart_mask_addr == 0 {
+        return Some(all_harts);
+    }
+    let host_va = two_stage_translation(guest.guest_id, hart_mask_addr, vsatp::read().bits(), &guest.gpm)?;
+    let hart_mask = unsafe { core::ptr::read_unaligned(host_va as *const usize) };
+    // bits of harts that do not exist are ignored
+    Some(hart_mask & all_harts)
+}
+
+/// SBI v0.1 calls, all effects are scoped to calling guest
+pub fn sbi_legacy_handler<G: GuestPageTable>(
+    host_vmm: &mut HostVmm<PageTableSv39, G>,
+    ctx: &TrapContext,
+) -> SbiRet {
+    let ext_id = ctx.x[GprIndex::A7 as usize];
+    let a0 = ctx.x[GprIndex::A0 as usize];
+    let a1 = ctx.x[GprIndex::A1 as usize];
+    let a2 = ctx.x[GprIndex::A2 as usize];
+    let a3 = ctx.x[GprIndex::A3 as usize];
+    let hart_id = host_vmm.hart_id;
+    let guest_id = host_vmm.guest_id;
+    let guest = host_vmm.guests[guest_id].as_mut().unwrap();
+    match ext_id {
+        SBI_SET_TIMER => {
+            guest.pmu.count_fw_event(SBI_PMU_FW_SET_TIMER, 0, 1);
+            sbi_legacy_set_time(host_vmm, a0)
+        }
+        SBI_CONSOLE_PUTCHAR => sbi_console_putchar_handler(host_vmm, a0),
+        SBI_CONSOLE_GETCHAR => {
+            // legacy calls return value in `a0`
+            let sbi_ret = sbi_console_getchar_handler();
+            SbiRet { error: sbi_ret.value, value: 0 }
+        }
+        SBI_CLEAR_IPI => {
+            unsafe { hvip::clear_vssip() };
+            SbiRet::success(0)
+        }
+        SBI_SEND_IPI => match read_legacy_hart_mask(guest, a0) {
+            Some(vcpu_mask) => {
+                guest_send_ipi(guest, vcpu_mask);
+                SbiRet::success(0)
+            }
+            None => SbiRet::error(SBI_ERR_INVALID_ADDRESS),
+        },
+        SBI_REMOTE_FENCE_I | SBI_REMOTE_SFENCE_VMA | SBI_REMOTE_SFENCE_VMA_ASID => {
+            let fid = match ext_id {
+                SBI_REMOTE_FENCE_I => SBI_REMOTE_FENCE_I_FID,
+                SBI_REMOTE_SFENCE_VMA => SBI_REMOTE_SFENCE_VMA_FID,
+                _ => SBI_REMOTE_SFENCE_VMA_ASID_FID,
+            };
+            // a0: hart_mask, a1: start_addr, a2: size, a3: asid
+            match read_legacy_hart_mask(guest, a0) {
+                Some(vcpu_mask) => guest_remote_fence(guest, hart_id, fid, vcpu_mask, a1, a2, a3),
+                None => SbiRet::error(SBI_ERR_INVALID_ADDRESS),
+            }
+        }
+        SBI_SHUTDOWN => {
+            htracking!("Shutdown: guest {}", guest_id);
+            host_vmm.shutdown_guest(guest_id);
+            SbiRet::success(0)
+        }
+        _ => SbiRet::error(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+pub fn sbi_legacy_set_time<P: PageTable, G: GuestPageTable>(
+    host_vmm: &mut HostVmm<P, G>,
+    stime: usize,
+) -> SbiRet {
+    let sbi_ret = SbiRet {
+        error: SBI_SUCCESS,
+        value: 0,
+    };
+    set_guest_timer(host_vmm, stime);
     return sbi_ret;
 }
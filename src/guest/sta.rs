@@ -0,0 +1,79 @@
+//! Steal time accounting of SBI STA extension. Time a runnable vcpu spends
+//! descheduled is reported to guest in shared memory registered by
+//! `set_shmem`.
+
+use core::ptr::{addr_of_mut, write_volatile};
+
+use riscv::register::time;
+
+use crate::constants::CLOCK_FREQ;
+
+const NSEC_PER_SEC: usize = 1_000_000_000;
+
+/// layout of STA shared memory defined by SBI
+#[repr(C)]
+struct StaShmem {
+    sequence: u32,
+    flags: u32,
+    steal: u64,
+    preempted: u8,
+    pad: [u8; 47],
+}
+
+pub struct StealTime {
+    /// host address of shared memory
+    shmem: Option<usize>,
+    /// stolen time in nanoseconds
+    steal: u64,
+    /// `time` when vcpu was switched out while runnable
+    preempted_at: Option<usize>,
+}
+
+impl StealTime {
+    pub fn new() -> Self {
+        Self { shmem: None, steal: 0, preempted_at: None }
+    }
+
+    /// register shared memory at host address `shmem`, 64 bytes aligned
+    pub fn set_shmem(&mut self, shmem: usize) {
+        unsafe{ core::ptr::write_bytes(shmem as *mut StaShmem, 0, 1) };
+        self.shmem = Some(shmem);
+        self.update(false);
+    }
+
+    pub fn disable(&mut self) {
+        self.shmem = None;
+    }
+
+    /// vcpu is switched out while it still wants to run
+    pub fn preempt(&mut self) {
+        self.preempted_at = Some(time::read());
+        self.update(true);
+    }
+
+    /// vcpu is scheduled back in, account time since `preempt`
+    pub fn resume(&mut self) {
+        if let Some(preempted_at) = self.preempted_at.take() {
+            let ticks = time::read().wrapping_sub(preempted_at);
+            let nsecs = (ticks as u128 * NSEC_PER_SEC as u128 / CLOCK_FREQ as u128) as u64;
+            self.steal = self.steal.wrapping_add(nsecs);
+        }
+        self.update(false);
+    }
+
+    /// Write steal time into shared memory, `sequence` is odd while updating
+    /// so that guest can detect torn reads.
+    fn update(&self, preempted: bool) {
+        let Some(shmem) = self.shmem else {
+            return;
+        };
+        let shmem = shmem as *mut StaShmem;
+        unsafe {
+            let sequence = addr_of_mut!((*shmem).sequence);
+            write_volatile(sequence, sequence.read_volatile().wrapping_add(1));
+            write_volatile(addr_of_mut!((*shmem).steal), self.steal);
+            write_volatile(addr_of_mut!((*shmem).preempted), preempted as u8);
+            write_volatile(sequence, sequence.read_volatile().wrapping_add(1));
+        }
+    }
+}
@@ -1,16 +1,175 @@
 use alloc::collections::VecDeque;
 
+use crate::constants::csr::hvip;
+
+use super::context::{GuestFpState, GuestVectorState, GuestVsCsrs};
+use super::sta::StealTime;
+use super::vmexit::TrapContext;
+
+/// supervisor software interrupt sent by `sbi_send_ipi`
+pub const VCPU_EVENT_IPI: u32 = 0;
+/// supervisor timer interrupt, deadline set by `sbi_set_timer` passed
+pub const VCPU_EVENT_TIMER: u32 = 1;
+/// external interrupt in IMSIC guest interrupt file, delivered through
+/// `hstatus.VGEIN` by hardware, the vcpu only needs to be waken up
+pub const VCPU_EVENT_GEI: u32 = 2;
+/// deadline of `vstimecmp` passed while vcpu waits in `wfi`, raised by
+/// hardware once vcpu runs, the vcpu only needs to be waken up
+pub const VCPU_EVENT_WAKE: u32 = 3;
+
+/// vcpu state defined by SBI HSM extension
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VCpuState {
+    Started = 0,
+    Stopped = 1,
+    StartPending = 2,
+    StopPending = 3,
+    Suspended = 4,
+    SuspendPending = 5,
+    ResumePending = 6,
+}
+
 pub struct VCpu {
+    /// virtual hart id seen by guest
     pub hart: usize,
-    /// pending interrupts
-    pub pending_events: VecDeque<u32>
+    /// physical hart which the vcpu is bound to
+    pub phys_hart: usize,
+    /// hsm state
+    pub state: VCpuState,
+    /// start address passed by `hart_start` or non-retentive `hart_suspend`
+    pub start_addr: usize,
+    /// opaque argument passed to guest in `a1`
+    pub opaque: usize,
+    /// whether the vcpu should resume from `start_addr` after suspended
+    pub non_retentive: bool,
+    /// saved trap context when vcpu is not running
+    pub ctx: Option<TrapContext>,
+    /// saved VS-level CSRs when vcpu is not running
+    pub vs_csrs: GuestVsCsrs,
+    /// saved FP registers when vcpu is not running
+    pub fp: GuestFpState,
+    /// saved vector registers when vcpu is not running
+    pub vector: GuestVectorState,
+    /// saved virtual interrupt pending bits when vcpu is not running
+    pub hvip: usize,
+    /// pending interrupts, injected into `hvip` on next entry into vcpu
+    pub pending_events: VecDeque<u32>,
+    /// steal time reported to guest
+    pub sta: StealTime,
+    /// IMSIC guest interrupt file selected by `hstatus.VGEIN`
+    pub vgein: Option<usize>,
+    /// `mtimecmp` of virtual CLINT in guest time
+    pub mtimecmp: usize,
+    /// vcpu is parked by trapped `wfi` until an interrupt arrives
+    pub wfi: bool,
+    /// guest TLB entries are flushed on next entry into vcpu
+    pub tlb_flush: bool
 }
 
 impl VCpu {
-    pub fn new(hart: usize) -> Self {
+    pub fn new(hart: usize, phys_hart: usize) -> Self {
         Self{
             hart,
-            pending_events: VecDeque::new()
+            phys_hart,
+            state: VCpuState::Stopped,
+            start_addr: 0,
+            opaque: 0,
+            non_retentive: false,
+            ctx: None,
+            vs_csrs: GuestVsCsrs::default(),
+            fp: GuestFpState::default(),
+            vector: GuestVectorState::new(),
+            hvip: 0,
+            pending_events: VecDeque::new(),
+            sta: StealTime::new(),
+            vgein: None,
+            mtimecmp: usize::MAX,
+            wfi: false,
+            tlb_flush: false
+        }
+    }
+
+    /// whether the vcpu can be scheduled on the physical hart
+    pub fn is_runnable(&self) -> bool {
+        match self.state {
+            VCpuState::Started => !self.wfi || self.has_pending_irq(),
+            VCpuState::StartPending => true,
+            VCpuState::Suspended => self.has_pending_irq(),
+            _ => false
+        }
+    }
+
+    /// suspended vcpu will be waken up by any pending interrupt
+    pub fn has_pending_irq(&self) -> bool {
+        self.hvip != 0 || !self.pending_events.is_empty()
+    }
+
+    /// queue an event which will be injected on next entry into vcpu
+    pub fn push_event(&mut self, event: u32) {
+        if !self.pending_events.contains(&event) {
+            self.pending_events.push_back(event);
+        }
+    }
+
+    /// drop pending timer interrupt when vcpu sets next timer,
+    /// must be called when vcpu is running
+    pub fn clear_timer(&mut self) {
+        self.pending_events.retain(|&event| event != VCPU_EVENT_TIMER);
+        unsafe{ hvip::write(hvip::read() & !hvip::VSTIP) };
+    }
+
+    /// drop pending timer interrupt of vcpu which is not running
+    pub fn clear_saved_timer(&mut self) {
+        self.pending_events.retain(|&event| event != VCPU_EVENT_TIMER);
+        self.hvip &= !hvip::VSTIP;
+    }
+
+    /// drop pending software interrupt, `running` if vcpu is running
+    pub fn clear_ipi(&mut self, running: bool) {
+        self.pending_events.retain(|&event| event != VCPU_EVENT_IPI);
+        if running {
+            unsafe{ hvip::write(hvip::read() & !hvip::VSSIP) };
+        } else {
+            self.hvip &= !hvip::VSSIP;
+        }
+    }
+
+    /// whether software interrupt is pending, `running` if vcpu is running
+    pub fn ipi_pending(&self, running: bool) -> bool {
+        let hvip = if running { hvip::read() } else { self.hvip };
+        hvip & hvip::VSSIP != 0 || self.pending_events.contains(&VCPU_EVENT_IPI)
+    }
+
+    /// inject all pending events into `hvip`, must be called when vcpu is running
+    pub fn inject_pending_events(&mut self) {
+        while let Some(event) = self.pending_events.pop_front() {
+            match event {
+                VCPU_EVENT_IPI => unsafe{ hvip::write(hvip::read() | hvip::VSSIP) },
+                VCPU_EVENT_TIMER => unsafe{ hvip::write(hvip::read() | hvip::VSTIP) },
+                VCPU_EVENT_GEI | VCPU_EVENT_WAKE => {},
+                _ => hwarning!("vcpu {}: unknown event {}", self.hart, event)
+            }
         }
     }
-}
\ No newline at end of file
+
+    /// prepare trap context for a vcpu started by `hart_start` or
+    /// resumed from non-retentive suspend, `a0` is hart id and `a1` is opaque
+    pub fn reset_context(&mut self, hgatp: usize, kernel_sp: usize, trap_handler: usize) {
+        let mut ctx = TrapContext::initialize_context(
+            self.start_addr,
+            0,
+            hgatp,
+            kernel_sp,
+            trap_handler
+        );
+        ctx.x[10] = self.hart;
+        ctx.x[11] = self.opaque;
+        self.ctx = Some(ctx);
+        // vcpu starts with `satp` is bare and all interrupts are disabled
+        self.vs_csrs = GuestVsCsrs::default();
+        self.fp = GuestFpState::default();
+        self.vector.reset();
+        self.hvip = 0;
+    }
+}
@@ -1,21 +1,27 @@
 use core::arch::{asm, global_asm};
 
+use crate::constants::csr::{self, counter, sip::SGEI_CODE, vsstatus};
 use crate::constants::layout::{GUEST_DTB_ADDR, TRAMPOLINE, TRAP_CONTEXT};
-use crate::device_emu::plic::is_plic_access;
+use crate::device_emu::mmio::MmioAccess;
 use crate::guest::page_table::GuestPageTable;
-use crate::guest::pmap::{decode_inst, two_stage_translation};
+use crate::guest::pmap::decode_inst;
+use crate::mm::MemorySet;
 use crate::hypervisor::{HostVmm, HOST_VMM};
-use crate::page_table::{PageTable, PageTableSv39};
+use crate::page_table::{translate_guest_va, PageTable, PageTableSv39, VirtPageNum};
 use crate::{VmmError, VmmResult};
 
 use riscv::register::scause::{Exception, Interrupt, Trap};
+use riscv_decode::Instruction;
 use riscv::register::{
-    hgatp, htinst, htval, hvip, scause, sepc, sie, sscratch, stval, stvec, vsatp, vstvec,
+    htinst, htval, scause, sepc, sie, sscratch, stval, stvec, time, vsatp, vstvec,
 };
+use riscv::register::sstatus::SPP;
 
 pub use super::context::TrapContext;
 use super::pmap::fast_two_stage_translation;
 use super::sbi::sbi_vs_handler;
+use super::pmu::PMU_PLATFORM_GUEST_PAGE_FAULT;
+use crate::sbi::{SBI_PMU_FW_MISALIGNED_LOAD, SBI_PMU_FW_MISALIGNED_STORE, SBI_PMU_FW_PLATFORM};
 
 global_asm!(include_str!("trap.S"));
 
@@ -55,89 +61,458 @@ fn set_user_trap_entry() {
     }
 }
 
-fn privileged_inst_handler(_ctx: &mut TrapContext) -> VmmResult {
-    todo!()
+/// exception codes injected into guest
+const EXC_INST_ACCESS_FAULT: usize = 1;
+const EXC_ILLEGAL_INSTRUCTION: usize = 2;
+const EXC_LOAD_ACCESS_FAULT: usize = 5;
+const EXC_STORE_ACCESS_FAULT: usize = 7;
+const EXC_LOAD_PAGE_FAULT: usize = 13;
+const EXC_STORE_PAGE_FAULT: usize = 15;
+
+/// `htinst` of guest page fault raised by implicit read or write(A/D bits update)
+/// of VS-stage page table walk
+const HTINST_PSEUDO_WALK_READ: usize = 0x3000;
+const HTINST_PSEUDO_WALK_WRITE: usize = 0x3020;
+
+/// operation of CSR instruction
+#[derive(Clone, Copy)]
+enum CsrOp {
+    Write,
+    Set,
+    Clear,
+}
+
+/// fetch the instruction which causes virtual instruction exception
+fn fetch_virtual_inst<P: PageTable, G: GuestPageTable>(
+    host_vmm: &HostVmm<P, G>,
+    ctx: &TrapContext,
+) -> VmmResult<usize> {
+    // `stval` holds the instruction bits if hardware provides them
+    let inst = stval::read();
+    if inst != 0 {
+        return Ok(inst);
+    }
+    if let Some(host_inst_addr) = fast_two_stage_translation::<PageTableSv39>(
+        host_vmm.guest_id,
+        ctx.sepc,
+        vsatp::read().bits(),
+    ) {
+        Ok(unsafe { core::ptr::read(host_inst_addr as *const u32) } as usize)
+    } else {
+        herror!("inst addr: {:#x}", ctx.sepc);
+        Err(VmmError::TranslationError)
+    }
+}
+
+/// read CSR virtualized by hypervisor, `None` if it is not virtualized
+fn read_virtual_csr<P: PageTable, G: GuestPageTable>(host_vmm: &HostVmm<P, G>, csr: usize) -> Option<usize> {
+    match csr {
+        // trapped under `hstatus.VTVM`
+        csr::satp => Some(vsatp::read().bits()),
+        csr::time => {
+            let guest = host_vmm.guests[host_vmm.guest_id].as_ref()?;
+            Some(time::read().wrapping_add(guest.time_delta))
+        }
+        csr::cycle | csr::instret => counter::read(csr),
+        // trapped under `hcounteren`, only counters configured through SBI PMU count
+        csr::hpmcounter3..=csr::hpmcounter31 => {
+            let guest = host_vmm.guests[host_vmm.guest_id].as_ref()?;
+            Some(guest.pmu.read_hw_counter(csr))
+        }
+        _ => None,
+    }
+}
+
+/// write CSR virtualized by hypervisor, return false if it is read only or not virtualized
+fn write_virtual_csr(csr: usize, value: usize) -> bool {
+    match csr {
+        csr::satp => {
+            unsafe { asm!("csrw vsatp, {}", in(reg) value) };
+            true
+        }
+        _ => false,
+    }
+}
+
+/// emulate CSR instruction, return false if the CSR can not be accessed by guest
+fn emulate_csr<P: PageTable, G: GuestPageTable>(
+    host_vmm: &HostVmm<P, G>,
+    ctx: &mut TrapContext,
+    csr: usize,
+    rd: usize,
+    operand: usize,
+    op: CsrOp,
+    write: bool,
+) -> bool {
+    let Some(old) = read_virtual_csr(host_vmm, csr) else {
+        return false;
+    };
+    if write {
+        let new = match op {
+            CsrOp::Write => operand,
+            CsrOp::Set => old | operand,
+            CsrOp::Clear => old & !operand,
+        };
+        if !write_virtual_csr(csr, new) {
+            return false;
+        }
+    }
+    if rd != 0 {
+        ctx.x[rd] = old;
+    }
+    true
+}
+
+/// emulate `sret` of guest trapped under `hstatus.VTSR`
+fn emulate_sret(ctx: &mut TrapContext) {
+    let mut status = vsstatus::read();
+    let spp = status & vsstatus::SPP != 0;
+    // SIE <- SPIE, SPIE <- 1, SPP <- U
+    if status & vsstatus::SPIE != 0 {
+        status |= vsstatus::SIE;
+    } else {
+        status &= !vsstatus::SIE;
+    }
+    status |= vsstatus::SPIE;
+    status &= !vsstatus::SPP;
+    let vsepc: usize;
+    unsafe {
+        vsstatus::write(status);
+        asm!("csrr {}, vsepc", out(reg) vsepc);
+    }
+    ctx.sstatus.set_spp(if spp { SPP::Supervisor } else { SPP::User });
+    ctx.sepc = vsepc;
 }
 
-pub fn guest_page_fault_handler<P: PageTable, G: GuestPageTable>(
+/// emulate `sfence.vma` of guest trapped under `hstatus.VTVM`
+fn emulate_sfence_vma(ctx: &TrapContext, rs1: usize, rs2: usize) {
+    let (vaddr, asid) = (ctx.x[rs1], ctx.x[rs2]);
+    unsafe {
+        match (rs1, rs2) {
+            (0, 0) => core::arch::riscv64::hfence_vvma_all(),
+            (0, _) => core::arch::riscv64::hfence_vvma_asid(asid),
+            (_, 0) => core::arch::riscv64::hfence_vvma_vaddr(vaddr),
+            _ => core::arch::riscv64::hfence_vvma(vaddr, asid),
+        }
+    }
+}
+
+fn privileged_inst_handler<P: PageTable, G: GuestPageTable>(
     host_vmm: &mut HostVmm<P, G>,
     ctx: &mut TrapContext,
 ) -> VmmResult {
-    let addr = htval::read() << 2;
-    if is_plic_access(addr) {
-        let mut inst = htinst::read();
-        if inst == 0 {
-            // If htinst does not provide information about the trap,
-            // we must read the instruction from guest's memory manually
-            let inst_addr = ctx.sepc;
-            // let gpm = &host_vmm.guests[host_vmm.guest_id].as_ref().unwrap().gpm;
-            if let Some(host_inst_addr) = fast_two_stage_translation::<PageTableSv39>(
-                host_vmm.guest_id,
-                inst_addr,
-                vsatp::read().bits(),
-            ) {
-                inst = unsafe { core::ptr::read(host_inst_addr as *const usize) };
+    let inst = fetch_virtual_inst(host_vmm, ctx)?;
+    let (len, decoded) = decode_inst(inst);
+    // VU-mode may only read counters guest enabled for it, other trapped
+    // instructions are illegal there
+    let user = ctx.sstatus.spp() == SPP::User;
+    let decoded = decoded.filter(|inst| !user || is_user_counter_read(inst));
+    let emulated = match decoded {
+        Some(Instruction::Wfi) => {
+            ctx.sepc += len;
+            host_vmm.vcpu_wfi();
+            return Ok(());
+        }
+        Some(Instruction::SfenceVma(i)) => {
+            emulate_sfence_vma(ctx, i.rs1() as usize, i.rs2() as usize);
+            true
+        }
+        Some(Instruction::Sret) => {
+            emulate_sret(ctx);
+            return Ok(());
+        }
+        Some(Instruction::Csrrw(i)) => {
+            let operand = ctx.x[i.rs1() as usize];
+            emulate_csr(host_vmm, ctx, i.csr() as usize, i.rd() as usize, operand, CsrOp::Write, true)
+        }
+        Some(Instruction::Csrrs(i)) => {
+            let operand = ctx.x[i.rs1() as usize];
+            emulate_csr(host_vmm, ctx, i.csr() as usize, i.rd() as usize, operand, CsrOp::Set, i.rs1() != 0)
+        }
+        Some(Instruction::Csrrc(i)) => {
+            let operand = ctx.x[i.rs1() as usize];
+            emulate_csr(host_vmm, ctx, i.csr() as usize, i.rd() as usize, operand, CsrOp::Clear, i.rs1() != 0)
+        }
+        Some(Instruction::Csrrwi(i)) => {
+            let operand = i.zimm() as usize;
+            emulate_csr(host_vmm, ctx, i.csr() as usize, i.rd() as usize, operand, CsrOp::Write, true)
+        }
+        Some(Instruction::Csrrsi(i)) => {
+            let operand = i.zimm() as usize;
+            emulate_csr(host_vmm, ctx, i.csr() as usize, i.rd() as usize, operand, CsrOp::Set, operand != 0)
+        }
+        Some(Instruction::Csrrci(i)) => {
+            let operand = i.zimm() as usize;
+            emulate_csr(host_vmm, ctx, i.csr() as usize, i.rd() as usize, operand, CsrOp::Clear, operand != 0)
+        }
+        _ => false,
+    };
+    if emulated {
+        ctx.sepc += len;
+    } else {
+        hdebug!("inject illegal instruction {:#x} at {:#x}", inst, ctx.sepc);
+        inject_exception(ctx, EXC_ILLEGAL_INSTRUCTION, inst);
+    }
+    Ok(())
+}
+
+/// whether `inst` only reads a counter CSR which guest lets VU-mode read in `scounteren`
+fn is_user_counter_read(inst: &Instruction) -> bool {
+    let csr = match inst {
+        Instruction::Csrrs(i) | Instruction::Csrrc(i) if i.rs1() == 0 => i.csr(),
+        Instruction::Csrrsi(i) | Instruction::Csrrci(i) if i.zimm() == 0 => i.csr(),
+        _ => return false,
+    };
+    counter::user_enabled(csr as usize)
+}
+
+/// fetch and decode the trapped load or store of guest
+fn decode_mmio_access<P: PageTable, G: GuestPageTable>(
+    host_vmm: &mut HostVmm<P, G>,
+    ctx: &mut TrapContext,
+) -> VmmResult<MmioAccess> {
+    let inst = htinst::read();
+    let access = if inst == 0 {
+        // If htinst does not provide information about the trap,
+        // we must read the instruction from guest's memory manually
+        let inst_addr = ctx.sepc;
+        if let Some(host_inst_addr) = fast_two_stage_translation::<PageTableSv39>(
+            host_vmm.guest_id,
+            inst_addr,
+            vsatp::read().bits(),
+        ) {
+            // compressed instruction may be the last one of a page
+            let i1 = unsafe { core::ptr::read(host_inst_addr as *const u16) } as u32;
+            let inst = if i1 & 0b11 == 0b11 {
+                unsafe { core::ptr::read(host_inst_addr as *const u32) }
             } else {
-                herror!("inst addr: {:#x}", inst_addr);
-                return Err(VmmError::TranslationError);
-            }
-        } else if inst == 0x3020 || inst == 0x3000 {
-            // TODO: we should reinject this in the guest as a fault access
-            herror!("fault on 1st stage page table walk");
-            return Err(VmmError::PseudoInst);
+                i1
+            };
+            MmioAccess::from_raw(inst)
         } else {
-            // If htinst is valid and is not a pseudo instructon make sure
-            // the opcode is valid even if it was a compressed instruction,
-            // but before save the real instruction size.
+            herror!("inst addr: {:#x}", inst_addr);
+            return Err(VmmError::TranslationError);
         }
-        let (len, inst) = decode_inst(inst);
-        if let Some(inst) = inst {
-            // htracking!("inst: {:?}", inst);
-            host_vmm.handle_plic_access(ctx, addr, inst)?;
-            ctx.sepc += len;
-        } else {
-            return Err(VmmError::DecodeInstError);
+    } else if inst == HTINST_PSEUDO_WALK_READ || inst == HTINST_PSEUDO_WALK_WRITE {
+        return Err(VmmError::PseudoInst);
+    } else {
+        // htinst is a transformed instruction, whose bit 1 tells
+        // whether the trapped instruction is compressed
+        MmioAccess::from_htinst(inst)
+    };
+    access.ok_or(VmmError::DecodeInstError)
+}
+
+pub fn guest_page_fault_handler<G: GuestPageTable>(
+    host_vmm: &mut HostVmm<PageTableSv39, G>,
+    ctx: &mut TrapContext,
+) -> VmmResult {
+    let addr = htval::read() << 2 | stval::read() & 0x3;
+    let inst = htinst::read();
+    if inst == HTINST_PSEUDO_WALK_READ || inst == HTINST_PSEUDO_WALK_WRITE {
+        // page table of guest is out of its memory, the access faults in guest
+        hwarning!("fault on 1st stage page table walk: addr: {:#x}, sepc: {:#x}", addr, ctx.sepc);
+        inject_exception(ctx, guest_access_fault(), stval::read());
+        return Ok(());
+    }
+    if !host_vmm.is_mmio_access(addr) {
+        // nothing is mapped at `addr` for guest, the access faults in guest
+        hwarning!("access to unmapped addr: {:#x}, sepc: {:#x}", addr, ctx.sepc);
+        inject_exception(ctx, guest_access_fault(), stval::read());
+        return Ok(());
+    }
+    match decode_mmio_access(host_vmm, ctx) {
+        Ok(access) => {
+            // device may restart the vcpu, e.g. test finisher rebooting guest
+            ctx.sepc += access.len;
+            host_vmm.handle_mmio_access(ctx, addr, access)
+        }
+        Err(err) => {
+            // instruction the guest accesses device with is not emulated
+            hwarning!("failed to decode mmio access: {:?}, addr: {:#x}, sepc: {:#x}", err, addr, ctx.sepc);
+            inject_exception(ctx, guest_access_fault(), stval::read());
+            Ok(())
         }
-        Ok(())
+    }
+}
+
+/// Instruction fetch of guest faulting in G-stage. Guest RAM is mapped
+/// executable when guest is created, so the fetch is from a device or region
+/// guest does not own and faults in guest.
+pub fn inst_guest_page_fault_handler<P: PageTable, G: GuestPageTable>(
+    _host_vmm: &mut HostVmm<P, G>,
+    ctx: &mut TrapContext,
+) {
+    let addr = htval::read() << 2 | stval::read() & 0x3;
+    let inst = htinst::read();
+    if inst == HTINST_PSEUDO_WALK_READ || inst == HTINST_PSEUDO_WALK_WRITE {
+        hwarning!("fault on 1st stage page table walk: addr: {:#x}, sepc: {:#x}", addr, ctx.sepc);
+    } else {
+        hwarning!("guest fetches from {:#x} out of its RAM, sepc: {:#x}", addr, ctx.sepc);
+    }
+    inject_exception(ctx, EXC_INST_ACCESS_FAULT, stval::read());
+}
+
+/// Guest physical address of load or store to `va` of guest, translated by
+/// VS-stage page table and checked against the leaf PTE as hardware does for
+/// privilege of guest. `Err` holds the page fault the access raises in guest.
+fn translate_guest_data(guest_id: usize, va: usize, is_store: bool, user: bool) -> Result<usize, usize> {
+    let fault = if is_store { EXC_STORE_PAGE_FAULT } else { EXC_LOAD_PAGE_FAULT };
+    let vsatp = vsatp::read().bits();
+    // Bare mode
+    if vsatp >> 60 == 0 {
+        return Ok(va);
+    }
+    let guest_root = (vsatp & 0xfff_ffff_ffff) << 12;
+    let translation = translate_guest_va::<PageTableSv39>(guest_id, guest_root, va).ok_or(fault)?;
+    let pte = translation.pte;
+    let status = vsstatus::read();
+    let privileged = if user {
+        pte.is_user()
+    } else {
+        !pte.is_user() || status & vsstatus::SUM != 0
+    };
+    // A and D are not updated by hardware for guest, clear ones fault
+    let permitted = if is_store {
+        pte.writable() && pte.dirty()
+    } else {
+        pte.readable() || pte.executable() && status & vsstatus::MXR != 0
+    };
+    if privileged && permitted && pte.accessed() {
+        Ok(translation.guest_pa)
     } else {
-        herror!("addr: {:#x}, sepc: {:#x}", addr, ctx.sepc);
-        Err(VmmError::DeviceNotFound)
-        // todo: handle other device
+        Err(fault)
     }
 }
 
+/// Emulate misaligned load or store of guest byte by byte, guest kernels
+/// built without strict alignment rely on it. Each byte is checked like the
+/// access would be by hardware, bytes guest can not access raise page fault
+/// and bytes not in guest RAM or not writable in G-stage raise access fault.
+pub fn misaligned_access_handler<P: PageTable, G: GuestPageTable>(
+    host_vmm: &mut HostVmm<P, G>,
+    ctx: &mut TrapContext,
+) -> VmmResult {
+    let vaddr = stval::read();
+    let Ok(access) = decode_mmio_access(host_vmm, ctx) else {
+        // floating point loads and stores are not emulated
+        forward_exception(ctx);
+        return Ok(());
+    };
+    let guest_id = host_vmm.guest_id;
+    let guest = host_vmm.guests[guest_id].as_mut().unwrap();
+    let user = ctx.sstatus.spp() == SPP::User;
+    let mut host_pa = [0usize; 8];
+    for (i, pa) in host_pa[..access.width].iter_mut().enumerate() {
+        let va = vaddr.wrapping_add(i);
+        let guest_pa = match translate_guest_data(guest_id, va, access.is_store, user) {
+            Ok(guest_pa) => guest_pa,
+            Err(cause) => {
+                inject_exception(ctx, cause, va);
+                return Ok(());
+            }
+        };
+        let pte = guest
+            .gpm
+            .translate(VirtPageNum::from(guest_pa >> 12))
+            .filter(|pte| pte.is_valid() && guest.is_ram(guest_pa))
+            .filter(|pte| if access.is_store { pte.writable() } else { pte.readable() });
+        let Some(pte) = pte else {
+            let cause = if access.is_store { EXC_STORE_ACCESS_FAULT } else { EXC_LOAD_ACCESS_FAULT };
+            inject_exception(ctx, cause, va);
+            return Ok(());
+        };
+        *pa = pte.ppn().0 << 12 | guest_pa & 0xfff;
+    }
+    if access.is_store {
+        let value = access.store_value(ctx);
+        for (i, &pa) in host_pa[..access.width].iter().enumerate() {
+            unsafe { core::ptr::write_volatile(pa as *mut u8, (value >> (8 * i)) as u8) };
+        }
+        guest.misaligned_stores += 1;
+        guest.pmu.count_fw_event(SBI_PMU_FW_MISALIGNED_STORE, 0, 1);
+    } else {
+        let value = host_pa[..access.width]
+            .iter()
+            .enumerate()
+            .fold(0, |value, (i, &pa)| {
+                value | (unsafe { core::ptr::read_volatile(pa as *const u8) } as usize) << (8 * i)
+            });
+        access.complete_load(ctx, value);
+        guest.misaligned_loads += 1;
+        guest.pmu.count_fw_event(SBI_PMU_FW_MISALIGNED_LOAD, 0, 1);
+    }
+    let count = guest.misaligned_loads + guest.misaligned_stores;
+    if count % 1000 == 1 {
+        htracking!(
+            "guest {} misaligned loads: {}, stores: {}, sepc: {:#x}, addr: {:#x}",
+            guest_id,
+            guest.misaligned_loads,
+            guest.misaligned_stores,
+            ctx.sepc,
+            vaddr
+        );
+    }
+    ctx.sepc += access.len;
+    Ok(())
+}
+
 /// handle interrupt request(current only external interrupt)
 pub fn handle_irq<P: PageTable, G: GuestPageTable>(
     host_vmm: &mut HostVmm<P, G>,
     _ctx: &mut TrapContext,
 ) {
     // TODO: handle other irq
-    // check external interrupt && handle
-    let host_plic = host_vmm.host_plic.as_mut().unwrap();
-    // get current guest context id
-    let context_id = 2 * host_vmm.guest_id + 1;
-    let claim_and_complete_addr = host_plic.base_addr + 0x0020_0004 + 0x1000 * context_id;
-    let irq = unsafe { core::ptr::read(claim_and_complete_addr as *const u32) };
-    host_plic.claim_complete[context_id] = irq;
-
-    // set external interrupt pending, which trigger guest interrupt
-    unsafe { hvip::set_vseip() };
+    // check external interrupt && handle, which is delivered to virtual PLIC of owner
+    host_vmm.handle_plic_irq();
 
     // set irq pending in host vmm
     host_vmm.irq_pending = true;
 }
 
-/// forward exception by setting `vsepc` & `vscause`
-pub fn forward_exception(ctx: &mut TrapContext) {
+/// Inject exception into guest as a trap taken into VS-mode, `vsstatus`
+/// records privilege of guest and disables its interrupts.
+pub fn inject_exception(ctx: &mut TrapContext, cause: usize, tval: usize) {
+    let mut status = vsstatus::read();
+    if ctx.sstatus.spp() == SPP::Supervisor {
+        status |= vsstatus::SPP;
+    } else {
+        status &= !vsstatus::SPP;
+    }
+    if status & vsstatus::SIE != 0 {
+        status |= vsstatus::SPIE;
+    } else {
+        status &= !vsstatus::SPIE;
+    }
+    status &= !vsstatus::SIE;
     unsafe {
+        vsstatus::write(status);
         asm!(
             "csrw vsepc, {sepc}",
             "csrw vscause, {scause}",
+            "csrw vstval, {stval}",
             sepc = in(reg) ctx.sepc,
-            scause = in(reg) scause::read().bits()
+            scause = in(reg) cause,
+            stval = in(reg) tval
         )
     }
-    ctx.sepc = vstvec::read().bits();
+    ctx.sstatus.set_spp(SPP::Supervisor);
+    // exceptions always go to base address of `vstvec`
+    ctx.sepc = vstvec::read().bits() & !0x3;
+}
+
+/// access fault of the same access type as current guest page fault
+fn guest_access_fault() -> usize {
+    match scause::read().cause() {
+        Trap::Exception(Exception::InstructionGuestPageFault) => EXC_INST_ACCESS_FAULT,
+        Trap::Exception(Exception::LoadGuestPageFault) => EXC_LOAD_ACCESS_FAULT,
+        _ => EXC_STORE_ACCESS_FAULT,
+    }
+}
+
+/// forward exception trapped from guest, which is handled by guest itself
+pub fn forward_exception(ctx: &mut TrapContext) {
+    inject_exception(ctx, scause::read().bits(), stval::read());
 }
 
 pub fn handle_internal_vmm_error(err: VmmError) {
@@ -158,32 +533,19 @@ pub unsafe fn trap_handler() -> ! {
             panic!("U-mode/VU-mode env call from VS-mode?");
         }
         Trap::Exception(Exception::VirtualSupervisorEnvCall) => {
-            if let Err(vmm_err) = sbi_vs_handler(ctx) {
+            if let Err(vmm_err) = sbi_vs_handler(&mut host_vmm, ctx) {
                 err = Some(vmm_err);
             }
             ctx.sepc += 4;
         }
         Trap::Exception(Exception::VirtualInstruction) => {
-            if let Err(vmm_err) = privileged_inst_handler(ctx) {
+            if let Err(vmm_err) = privileged_inst_handler(&mut host_vmm, ctx) {
                 err = Some(vmm_err);
             }
         }
         Trap::Exception(Exception::InstructionGuestPageFault) => {
-            let host_vmm = unsafe { HOST_VMM.get().unwrap().lock() };
-            let guest_id = host_vmm.guest_id;
-            let gpm = &host_vmm.guests[guest_id].as_ref().unwrap().gpm;
-            if let Some(host_va) =
-                two_stage_translation(guest_id, ctx.sepc, vsatp::read().bits(), gpm)
-            {
-                herror!("host va: {:#x}", host_va);
-            } else {
-                herror!("Fail to translate exception pc.");
-            }
-            panic!(
-                "InstructionGuestPageFault: sepc -> {:#x}, hgatp -> {:#x}",
-                ctx.sepc,
-                hgatp::read().bits()
-            );
+            inst_guest_page_fault_handler(&mut host_vmm, ctx);
+            host_vmm.guest_page_falut += 1;
         }
         Trap::Exception(Exception::LoadGuestPageFault)
         | Trap::Exception(Exception::StoreGuestPageFault) => {
@@ -191,6 +553,10 @@ pub unsafe fn trap_handler() -> ! {
                 err = Some(vmm_err);
             }
             host_vmm.guest_page_falut += 1;
+            let guest_id = host_vmm.guest_id;
+            if let Some(guest) = host_vmm.guests[guest_id].as_mut() {
+                guest.pmu.count_fw_event(SBI_PMU_FW_PLATFORM, PMU_PLATFORM_GUEST_PAGE_FAULT, 1);
+            }
             if host_vmm.guest_page_falut % 1000 == 0 {
                 htracking!(
                     "guest page fault: {}, addr: {:#x}",
@@ -205,17 +571,29 @@ pub unsafe fn trap_handler() -> ! {
             // htracking!("external irq: {}", host_vmm.external_irq);
         }
         Trap::Interrupt(Interrupt::SupervisorTimer) => {
-            // set guest timer interrupt pending
-            hvip::set_vstip();
-            // disable timer interrupt
-            sie::clear_stimer();
             host_vmm.timer_irq += 1;
             // if host_vmm.timer_irq % 1000 == 0 {
             //     htracking!("timer irq: {}", host_vmm.timer_irq);
             // }
+            host_vmm.handle_timer_irq();
+        }
+        Trap::Exception(Exception::LoadMisaligned)
+        | Trap::Exception(Exception::StoreMisaligned) => {
+            if let Err(vmm_err) = misaligned_access_handler(&mut host_vmm, ctx) {
+                err = Some(vmm_err);
+            }
+        }
+        Trap::Interrupt(_) if scause.code() == SGEI_CODE => {
+            host_vmm.handle_sgei();
         }
         _ => forward_exception(ctx),
     }
+    // vcpu may be stopped, suspended or its guest may be shut down
+    if !host_vmm.current_vcpu_running() {
+        host_vmm.schedule();
+    }
+    host_vmm.inject_pending_events();
+    host_vmm.update_hgeie();
     drop(host_vmm);
     if let Some(err) = err {
         // TODO: handler vmm error
@@ -234,7 +612,6 @@ pub unsafe fn hart_entry_1() -> ! {
         let hgatp = riscv::register::hgatp::Hgatp::from_bits(ctx.hgatp);
         hgatp.write();
         core::arch::riscv64::hfence_gvma_all();
-        assert_eq!(hgatp.bits(), riscv::register::hgatp::read().bits());
     }
     hart_entry_2()
 }
@@ -306,7 +683,6 @@ pub unsafe fn switch_to_guest() -> ! {
         let hgatp = riscv::register::hgatp::Hgatp::from_bits(ctx.hgatp);
         hgatp.write();
         core::arch::riscv64::hfence_gvma_all();
-        assert_eq!(hgatp.bits(), riscv::register::hgatp::read().bits());
     }
 
     extern "C" {
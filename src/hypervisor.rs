@@ -40,6 +40,9 @@ pub mod fdt {
 use arrayvec::ArrayVec;
 use fdt::Fdt;
 
+/// supervisor external interrupt, cause of IMSIC supervisor level files
+const IRQ_S_EXT: u32 = 9;
+
 #[derive(Clone, Debug)]
 pub struct Device {
     pub base_address: usize,
@@ -51,6 +54,8 @@ pub struct MachineMeta{
     pub physical_memory_offset: usize,
     pub physical_memory_size: usize,
 
+    pub harts: usize,
+
     pub virtio: ArrayVec<Device, 16>,
 
     pub test_finisher_address: Option<Device>,
@@ -61,7 +66,33 @@ pub struct MachineMeta{
 
     pub plic: Option<Device>,
 
+    /// guest `wfi` traps into hypervisor by `hypocaust,wfi-trap` in `/chosen`
+    pub wfi_trap: bool,
+
+    /// guest `sret`, `sfence.vma` and `satp` accesses trap into hypervisor
+    /// by `hypocaust,vm-trap` in `/chosen`
+    pub vm_trap: bool,
+
+    /// supervisor level APLIC domain
+    pub aplic: Option<Device>,
+
+    /// supervisor level IMSIC files of all harts
+    pub imsic: Option<Device>,
+
+    /// guest interrupt files of each hart are `1 << imsic_guest_bits` pages,
+    /// supervisor level file is the first one
+    pub imsic_guest_bits: usize,
+
     pub pci: Option<Device>,
+
+    /// SBI extensions turned on by `hypocaust,sbi-enable` in `/chosen`
+    pub sbi_enable: ArrayVec<usize, 16>,
+
+    /// SBI extensions turned off by `hypocaust,sbi-disable` in `/chosen`
+    pub sbi_disable: ArrayVec<usize, 16>,
+
+    /// PLIC sources of devices, plus `hypocaust,irqs` in `/chosen`
+    pub irqs: ArrayVec<usize, 64>,
 }
 
 impl MachineMeta {
@@ -73,6 +104,8 @@ impl MachineMeta {
             meta.physical_memory_offset = region.starting_address as usize;
             meta.physical_memory_size = region.size.unwrap();
         }
+        meta.harts = fdt.cpus().count();
+        hdebug!("harts: {}", meta.harts);
         // probe virtio mmio device
         for node in fdt.find_all_nodes("/soc/virtio_mmio") {
             if let Some(reg) = node.reg().and_then(|mut reg| reg.next()) {
@@ -81,7 +114,8 @@ impl MachineMeta {
                 hdebug!("virtio mmio addr: {:#x}, size: {:#x}", paddr, size);
                 meta.virtio.push(
                     Device { base_address: paddr, size }
-                )
+                );
+                meta.irqs.extend(node.interrupts().into_iter().flatten().take(1));
             }
         }
         meta.virtio.sort_unstable_by_key(|v| v.base_address);
@@ -103,6 +137,7 @@ impl MachineMeta {
                 let size = reg.size.unwrap();
                 hdebug!("UART addr: {:#x}, size: {:#x}", base_addr, size);
                 meta.uart = Some(Device { base_address: base_addr, size});
+                meta.irqs.extend(node.interrupts().into_iter().flatten().take(1));
             }
         }
 
@@ -126,6 +161,41 @@ impl MachineMeta {
             }
         }
 
+        // probe AIA, machine level APLIC domain has children and
+        // machine level IMSIC is wired to machine external interrupt
+        for node in fdt.find_all_nodes("/soc/aplic") {
+            if node.property("riscv,children").is_some() {
+                continue;
+            }
+            if let Some(reg) = node.reg().and_then(|mut reg| reg.next()) {
+                let base_addr = reg.starting_address as usize;
+                let size = reg.size.unwrap();
+                hdebug!("APLIC addr: {:#x}, size: {:#x}", base_addr, size);
+                meta.aplic = Some(Device { base_address: base_addr, size});
+            }
+        }
+
+        for node in fdt.find_all_nodes("/soc/imsics") {
+            let supervisor = node.property("interrupts-extended").map_or(false, |prop| {
+                // <phandle cause> of each hart
+                prop.value.chunks_exact(8).any(|cell| {
+                    u32::from_be_bytes([cell[4], cell[5], cell[6], cell[7]]) == IRQ_S_EXT
+                })
+            });
+            if !supervisor {
+                continue;
+            }
+            if let Some(reg) = node.reg().and_then(|mut reg| reg.next()) {
+                let base_addr = reg.starting_address as usize;
+                let size = reg.size.unwrap();
+                meta.imsic_guest_bits = node.property("riscv,guest-index-bits")
+                    .and_then(|prop| prop.as_usize())
+                    .unwrap_or(0);
+                hdebug!("IMSIC addr: {:#x}, size: {:#x}, guest index bits: {}", base_addr, size, meta.imsic_guest_bits);
+                meta.imsic = Some(Device { base_address: base_addr, size});
+            }
+        }
+
         for node in fdt.find_all_nodes("/soc/pci") {
             if let Some(reg) = node.reg().and_then(|mut reg| reg.next()) {
                 let base_addr = reg.starting_address as usize;
@@ -135,6 +205,34 @@ impl MachineMeta {
             }
         }
 
+        // probe per guest SBI extension config
+        if let Some(chosen) = fdt.find_node("/chosen") {
+            // interrupt sources routed to guest besides those of devices
+            if let Some(prop) = chosen.property("hypocaust,irqs") {
+                for cell in prop.value.chunks_exact(4) {
+                    let irq = u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]) as usize;
+                    if !meta.irqs.contains(&irq) && !meta.irqs.is_full() {
+                        meta.irqs.push(irq);
+                    }
+                }
+            }
+            hdebug!("irqs: {:?}", meta.irqs);
+            meta.wfi_trap = chosen.property("hypocaust,wfi-trap").is_some();
+            meta.vm_trap = chosen.property("hypocaust,vm-trap").is_some();
+            for (name, exts) in [
+                ("hypocaust,sbi-enable", &mut meta.sbi_enable),
+                ("hypocaust,sbi-disable", &mut meta.sbi_disable)
+            ] {
+                if let Some(prop) = chosen.property(name) {
+                    for cell in prop.value.chunks_exact(4).take(exts.capacity()) {
+                        let ext_id = u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]) as usize;
+                        hdebug!("{}: {:#x}", name, ext_id);
+                        exts.push(ext_id);
+                    }
+                }
+            }
+        }
+
         meta
     }
 }
@@ -144,14 +242,17 @@ impl MachineMeta {
 
 
 use arrayvec::ArrayVec;
-use riscv::register::{ hvip, sie };
+use riscv::register::{ hvip, sie, time };
 use spin::{ Once, Mutex };
 use crate::constants::MAX_GUESTS;
-use crate::constants::csr::{hedeleg, hideleg, hcounteren};
-use crate::device_emu::plic::PlicState;
-use crate::guest::{ page_table::GuestPageTable, Guest };
+use crate::constants::csr::{hedeleg, hideleg, hcounteren, henvcfg, hgeip, sip, vstimecmp, hvip as hvip_csr, sie as sie_ext};
+use crate::device_emu::aplic::AplicState;
+use crate::device_emu::imsic::probe_guest_files;
+use crate::device_emu::plic::{ PlicState, plic_s_context };
+use crate::guest::{ page_table::GuestPageTable, probe_vmid_bits, Guest, VCpuState };
 use crate::page_table::{ PageTable, PageTableSv39 };
 use crate::mm::HostMemorySet;
+use crate::timer::{ TimerQueue, TimerEvent, SCHED_TICK_INTERVAL };
 
 use self::fdt::MachineMeta;
 
@@ -164,29 +265,264 @@ pub struct HostVmm<P: PageTable, G: GuestPageTable> {
     pub hpm: HostMemorySet<P>,
     /// all guest structs
     pub guests: ArrayVec<Option<Guest<G>>, MAX_GUESTS>,
+    /// current physical hart id(single core)
+    pub hart_id: usize,
     /// current run guest id(single core)
     pub guest_id: usize,
     /// hypervisor emulated plic
     pub host_plic: Option<PlicState>,
+    /// supervisor level APLIC domain driven by hypervisor
+    pub host_aplic: Option<AplicState>,
+    /// IMSIC guest interrupt files of current hart
+    pub imsic_files: usize,
+    /// guest interrupt files assigned to vcpus
+    pub imsic_files_used: usize,
+    /// guest interrupt files masked in `hgeie` until their vcpu runs
+    pub gei_masked: usize,
 
     pub irq_pending: bool,
 
     pub timer_irq: usize,
     pub external_irq: usize,
     pub guest_page_falut: usize,
+    /// time ticks hart spent in host `wfi` with nothing to run
+    pub idle_time: usize,
+
+    /// guest which writes host console last
+    pub console_owner: usize,
+
+    /// guests program `vstimecmp` directly(Sstc)
+    pub sstc: bool,
+
+    /// timer events of vcpus on current hart and scheduler tick
+    pub timer_queue: TimerQueue,
+
+    /// VMIDLEN implemented by hart
+    pub vmid_bits: usize,
 }
 
-pub fn add_guest_queue(guest: Guest<PageTableSv39>) {
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    /// whether the vcpu which trapped into hypervisor is still running
+    pub fn current_vcpu_running(&self) -> bool {
+        match &self.guests[self.guest_id] {
+            Some(guest) => guest.vcpus[guest.vcpu_id].state == VCpuState::Started,
+            None => false
+        }
+    }
+
+    /// Switch to next runnable vcpu of current guest, or vcpu of another guest
+    /// if current guest has nothing to run. The hart idles until some vcpu
+    /// becomes runnable.
+    pub fn schedule(&mut self) {
+        while !self.try_schedule(false) {
+            self.idle();
+        }
+    }
+
+    /// Time slice of current guest is used up, run vcpu of the next guest
+    /// which has one runnable, or stay in current guest if no other has.
+    pub fn schedule_tick(&mut self) {
+        while !self.try_schedule(true) {
+            self.idle();
+        }
+    }
+
+    fn try_schedule(&mut self, rotate: bool) -> bool {
+        if !rotate && self.schedule_current_guest() {
+            return true;
+        }
+        if self.switch_guest() {
+            return true;
+        }
+        rotate && self.schedule_current_guest()
+    }
+
+    fn schedule_current_guest(&mut self) -> bool {
+        match self.guests[self.guest_id].as_mut() {
+            Some(guest) => guest.schedule(),
+            None => false
+        }
+    }
+
+    /// switch to the next guest after current one which has runnable vcpu
+    fn switch_guest(&mut self) -> bool {
+        let current = self.guest_id;
+        for i in 1..MAX_GUESTS {
+            let next = (current + i) % MAX_GUESTS;
+            let vcpu_id = self.guests[next].as_ref().and_then(|guest| guest.next_runnable_vcpu());
+            if let Some(vcpu_id) = vcpu_id {
+                // clock of preempted guest keeps running, the gap is reported
+                // to it as steal time
+                if let Some(guest) = self.guests[current].as_mut() {
+                    guest.save_vcpu();
+                    guest.pmu.save();
+                }
+                let guest = self.guests[next].as_mut().unwrap();
+                guest.pmu.restore();
+                guest.load_vcpu(vcpu_id, true);
+                self.guest_id = next;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Wait in host `wfi` until an interrupt arrives and handle it, vcpus
+    /// waken up by it are picked by next `try_schedule`.
+    fn idle(&mut self) {
+        self.update_hgeie();
+        let start = time::read();
+        unsafe{ core::arch::riscv64::wfi() };
+        self.idle_time += time::read() - start;
+        let pending = sip::read();
+        if pending & sip::STIP != 0 {
+            self.expire_timers();
+            self.program_timer();
+        }
+        if pending & sip::SEIP != 0 {
+            self.handle_plic_irq();
+        }
+        if pending & sip::SGEIP != 0 {
+            self.handle_sgei();
+        }
+        // parked running vcpu keeps its interrupts in `hvip`
+        if let Some(guest) = self.guests[self.guest_id].as_mut() {
+            let vcpu = &mut guest.vcpus[guest.vcpu_id];
+            if vcpu.wfi {
+                vcpu.hvip = hvip_csr::read();
+            }
+        }
+    }
+
+    /// Park running vcpu which executes `wfi` until its next interrupt,
+    /// the hart runs other vcpus meanwhile.
+    pub fn vcpu_wfi(&mut self) {
+        let sstc = self.sstc;
+        let guest_id = self.guest_id;
+        let Some(guest) = self.guests[guest_id].as_mut() else {
+            return;
+        };
+        let vcpu_id = guest.vcpu_id;
+        let vcpu = &mut guest.vcpus[vcpu_id];
+        let file_pending = vcpu.vgein.map_or(false, |vgein| hgeip::read() & 1 << vgein != 0);
+        if hvip_csr::read() & (hvip_csr::VSSIP | hvip_csr::VSTIP | hvip_csr::VSEIP) != 0
+            || !vcpu.pending_events.is_empty()
+            || file_pending
+        {
+            return;
+        }
+        vcpu.wfi = true;
+        vcpu.hvip = hvip_csr::read();
+        if sstc {
+            // `vstimecmp` does not raise host timer interrupt
+            let deadline = guest.guest_time_to_host(vstimecmp::read());
+            self.timer_queue.set(TimerEvent::Wake { guest_id, vcpu_id }, deadline);
+            self.program_timer();
+        }
+        self.schedule();
+    }
+
+    /// inject events queued for current vcpu before entering guest, and flush
+    /// its TLB entries if remote fence was deferred to it
+    pub fn inject_pending_events(&mut self) {
+        if let Some(guest) = self.guests[self.guest_id].as_mut() {
+            let vcpu_id = guest.vcpu_id;
+            guest.vcpus[vcpu_id].inject_pending_events();
+            // `hgatp` may still hold other guest, fence by VMID of guest
+            if core::mem::take(&mut guest.vcpus[vcpu_id].tlb_flush) {
+                unsafe{ core::arch::riscv64::hfence_gvma_vmid(guest.vmid) };
+            }
+        }
+    }
+
+    /// Tear down guest, other guests keep running. The hart switches away
+    /// when `schedule` is called, or the machine powers off with the last guest.
+    pub fn shutdown_guest(&mut self, guest_id: usize) {
+        self.reset_guest_plic(guest_id);
+        self.release_guest_irqs(guest_id);
+        self.reset_guest_aplic(guest_id, true);
+        self.detach_imsic(guest_id);
+        if let Some(mut guest) = self.guests[guest_id].take() {
+            hdebug!("guest {} shutdown", guest_id);
+            if guest_id == self.guest_id {
+                guest.pmu.reset();
+            }
+            self.timer_queue.cancel_guest(guest_id);
+            unsafe{ core::arch::riscv64::hfence_gvma_vmid(guest.vmid) };
+        }
+        // nothing is left to run, power off instead of idling forever
+        if self.guests.iter().all(|guest| guest.is_none()) {
+            hdebug!("all guests shutdown, power off");
+            crate::sbi::poweroff();
+        }
+    }
+
+    /// VMID of guest, VMID 0 is left for hypervisor. Guests share VMIDs when
+    /// hart implements fewer than guests, which is fine as switching `hgatp`
+    /// fences all guest TLB entries.
+    pub fn guest_vmid(&self, guest_id: usize) -> usize {
+        let nr_vmids = (1 << self.vmid_bits) - 1;
+        if nr_vmids == 0 {
+            return 0;
+        }
+        guest_id % nr_vmids + 1
+    }
+
+    /// Stop guest until `resume_guest`, its clock and timers stand still
+    /// meanwhile. The hart switches away when `schedule` is called.
+    pub fn pause_guest(&mut self, guest_id: usize) {
+        if let Some(guest) = self.guests[guest_id].as_mut() {
+            hdebug!("guest {} paused", guest_id);
+            guest.pause_clock();
+            self.timer_queue.park_guest(guest_id);
+        }
+    }
+
+    /// Let guest stopped by `pause_guest` run again from where its clock stopped,
+    /// its timers are postponed by the time it was paused.
+    pub fn resume_guest(&mut self, guest_id: usize) {
+        if let Some(guest) = self.guests[guest_id].as_mut() {
+            hdebug!("guest {} resumed", guest_id);
+            let paused = guest.resume_clock();
+            self.timer_queue.unpark_guest(guest_id, paused);
+            self.program_timer();
+        }
+    }
+
+    /// Reload guest image and dtb and restart guest. The boot vcpu is loaded
+    /// when `schedule` is called.
+    pub fn reboot_guest(&mut self, guest_id: usize) {
+        self.reset_guest_plic(guest_id);
+        self.reset_guest_aplic(guest_id, false);
+        if let Some(guest) = self.guests[guest_id].as_mut() {
+            hdebug!("guest {} reboot", guest_id);
+            guest.reboot();
+            self.timer_queue.cancel_guest(guest_id);
+        }
+    }
+}
+
+pub fn add_guest_queue(mut guest: Guest<PageTableSv39>) {
     let host_vmm = unsafe{ HOST_VMM.get_mut().unwrap() };
     let mut host_vmm = host_vmm.lock();
     let guest_id = guest.guest_id;
     assert!(guest_id < MAX_GUESTS);
+    for &irq in guest.guest_machine.irqs.iter() {
+        if host_vmm.host_aplic.is_some() {
+            host_vmm.assign_aplic_irq(irq, guest_id);
+        } else {
+            host_vmm.assign_irq(irq, guest_id);
+        }
+    }
+    host_vmm.attach_imsic(&mut guest);
+    guest.vmid = host_vmm.guest_vmid(guest_id);
     host_vmm.guests[guest_id] = Some(guest);
 }
 
 
-pub unsafe fn init_vmm(hpm: HostMemorySet<PageTableSv39>, host_machine: MachineMeta) {
-    // hedeleg: delegate some synchronous exceptions
+pub unsafe fn init_vmm(hart_id: usize, hpm: HostMemorySet<PageTableSv39>, host_machine: MachineMeta, sstc: bool) {
+    // hedeleg: delegate some synchronous exceptions,
+    // misaligned loads and stores are emulated by hypervisor
     hedeleg::write(
         hedeleg::INST_ADDR_MISALIGN |
         hedeleg::BREAKPOINT | 
@@ -208,26 +544,34 @@ pub unsafe fn init_vmm(hpm: HostMemorySet<PageTableSv39>, host_machine: MachineM
     hvip::clear_vssip();
     hvip::clear_vstip();
 
-    // When the hypervisor is initialized, it is necessary to write the `hcounteren` register to all 1, because it is possible to read the `time` register in VU mode or VS mode.(refs: The counter-enable register `hcounteren` is a 32-bit register that controls the availability of the hardware performance monitoring counters to the guest virtual machine.  
-    // When the CY, TM, IR, or HPMn bit in the hcounteren register is clear, attempts to read the
-    // cycle, time, instret, or hpmcountern register while V=1 will cause a virtual instruction exception
-    // if the same bit in mcounteren is 1. When one of these bits is set, access to the corresponding register
-    // is permitted when V=1, unless prevented for some other reason. In VU-mode, a counter is not
-    // readable unless the applicable bits are set in both `hcounteren` and `scounteren`.  
-    // `hcounteren` must be implemented. However, any of the bits may be read-only zero, indicating
-    // reads to the corresponding counter will cause an exception when V=1. Hence, they are effectively
-    // WARL fields.) 
-    hcounteren::write(0xffff_ffff);
+    // hcounteren: guest reads `cycle`, `time` and `instret` directly, reads of
+    // `hpmcounter3`~`hpmcounter31` raise virtual instruction exception and are
+    // emulated with counters configured by guest through SBI PMU
+    hcounteren::write(hcounteren::CY | hcounteren::TM | hcounteren::IR);
+
+    // henvcfg: guest timer interrupt is raised by `vstimecmp` without trapping
+    if sstc {
+        henvcfg::set(henvcfg::STCE);
+    }
 
     // enable all interupts
     sie::set_sext();
     sie::set_ssoft();
     sie::set_stimer();
 
+    // guest interrupt files of vcpus not running wake them up
+    let imsic_files = probe_guest_files();
+    if imsic_files != 0 {
+        sie_ext::set(sie_ext::SGEIE);
+    }
+
     core::arch::asm!(
         "csrw vsatp, 0"
     );
 
+    let vmid_bits = probe_vmid_bits();
+    hdebug!("VMIDLEN: {}", vmid_bits);
+
     // initialize HOST_VMM
     HOST_VMM.call_once(|| {
         let mut guests: ArrayVec<Option<Guest<PageTableSv39>>, MAX_GUESTS> = ArrayVec::new_const();
@@ -235,27 +579,60 @@ pub unsafe fn init_vmm(hpm: HostMemorySet<PageTableSv39>, host_machine: MachineM
             guests.push(None)
         }
 
+        // start scheduler tick
+        let mut timer_queue = TimerQueue::new();
+        timer_queue.set(TimerEvent::SchedTick, time::read() + SCHED_TICK_INTERVAL);
+
         let host_plic;
         if let Some(plic) = host_machine.clone().plic {
-            host_plic = Some(PlicState::new(plic.base_address));
+            // hypervisor claims all interrupts in its own context
+            let mut plic = PlicState::new(plic.base_address);
+            plic.set_threshold(plic_s_context(hart_id), 0);
+            host_plic = Some(plic);
         }else{
             host_plic = None;
         }
+        // guest sources are delivered to IMSIC guest interrupt files by MSI
+        let host_aplic;
+        match (host_machine.clone().aplic, &host_machine.imsic) {
+            (Some(aplic), Some(_)) => {
+                let mut aplic = AplicState::new(aplic.base_address);
+                aplic.init();
+                host_aplic = Some(aplic);
+            }
+            (Some(_), None) => {
+                hwarning!("APLIC without IMSIC is not supported");
+                host_aplic = None;
+            }
+            _ => host_aplic = None
+        }
         Mutex::new(
             HostVmm { 
                 host_machine,
                 hpm,
                 guests,
+                hart_id,
                 guest_id: 0,
                 host_plic,
+                host_aplic,
+                imsic_files,
+                imsic_files_used: 0,
+                gei_masked: 0,
                 irq_pending: false,
                 timer_irq: 0,
                 external_irq: 0,
-                guest_page_falut: 0
+                guest_page_falut: 0,
+                idle_time: 0,
+                console_owner: 0,
+                sstc,
+                timer_queue,
+                vmid_bits
             }
         )
     });
 
+    HOST_VMM.get_mut().unwrap().lock().program_timer();
+
     hdebug!("Initialize hypervisor environment");
 
 }
\ No newline at end of file
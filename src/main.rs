@@ -35,11 +35,12 @@ mod mm;
 mod page_table;
 mod sbi;
 mod sync;
+mod timer;
 
-use crate::constants::layout::{GUEST_DEFAULT_SIZE, GUEST_START_PA};
+use crate::constants::layout::{GUEST_DEFAULT_SIZE, GUEST_DTB_ADDR, GUEST_START_PA};
 use crate::constants::PAGE_SIZE;
 use crate::guest::vmexit::hart_entry_1;
-use crate::guest::Guest;
+use crate::guest::{Guest, GuestImage};
 use crate::hypervisor::{add_guest_queue, init_vmm, HOST_VMM};
 use crate::mm::{GuestMemorySet, HostMemorySet};
 use crate::page_table::PageTableSv39;
@@ -116,6 +117,11 @@ unsafe fn hentry(hart_id: usize, dtb: usize) -> ! {
             panic!("no RISC-V hypervisor H extension on current environment")
         }
         hdebug!("Hypocaust-2 > running with hardware RISC-V H ISA acceration!");
+        // detect sstc extension, guests program `vstimecmp` without trapping if exists
+        let sstc = detect::detect_sstc_extension();
+        if sstc {
+            hdebug!("Hypocaust-2 > Sstc extension detected, enable guest vstimecmp");
+        }
 
         // initialize heap
         hyp_alloc::heap_init();
@@ -126,12 +132,13 @@ unsafe fn hentry(hart_id: usize, dtb: usize) -> ! {
         let guest_machine = hypervisor::fdt::MachineMeta::parse(GUEST_DTB.as_ptr() as usize);
         // initialize vmm
         let hpm = HostMemorySet::<PageTableSv39>::new_host_vmm(&machine);
-        init_vmm(hpm, machine);
+        init_vmm(hart_id, hpm, machine, sstc);
         // create guest memory set
         let gpm = GuestMemorySet::<PageTableSv39>::new_guest_without_load(&guest_machine);
 
         let mut host_vmm = HOST_VMM.get_mut().unwrap().lock();
-        host_vmm.hpm.map_guest(GUEST_START_PA, GUEST_DEFAULT_SIZE);
+        // map guest dtb and guest memory
+        host_vmm.hpm.map_guest(GUEST_DTB_ADDR, GUEST_START_PA - GUEST_DTB_ADDR + GUEST_DEFAULT_SIZE);
         drop(host_vmm);
         // hypervisor enable paging
         mm::enable_paging();
@@ -139,9 +146,16 @@ unsafe fn hentry(hart_id: usize, dtb: usize) -> ! {
         guest::vmexit::trap_init();
         // memory translation test
         mm::remap_test();
+        // save guest image for reboot
+        let image = GuestImage::new(GUEST_START_PA, GUEST.len(), GUEST_DTB_ADDR, GUEST_DTB.len());
         // create guest struct
-        let guest = Guest::new(0, gpm, guest_machine);
+        let guest = Guest::new(0, gpm, guest_machine, Some(image));
         add_guest_queue(guest);
+        // load boot vcpu
+        let mut host_vmm = HOST_VMM.get_mut().unwrap().lock();
+        host_vmm.schedule();
+        host_vmm.update_hgeie();
+        drop(host_vmm);
         hdebug!("Jump to guest......");
         hart_entry_1()
     } else {
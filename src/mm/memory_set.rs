@@ -161,6 +161,20 @@ impl<P: PageTable> HostMemorySet<P> {
             )
         }
 
+        if let Some(aplic) = &machine.aplic {
+            hpm.push(
+                MapArea::new(
+                    aplic.base_address.into(),
+                    (aplic.base_address + aplic.size).into(),
+                    Some(aplic.base_address.into()),
+                    Some((aplic.base_address + aplic.size).into()),
+                    MapType::Linear,
+                    MapPermission::R | MapPermission::W,
+                ),
+                None,
+            );
+        }
+
         if let Some(plic) = &machine.plic {
             hpm.push(
                 MapArea::new(
@@ -317,20 +331,8 @@ impl<G: GuestPageTable> GuestMemorySet<G> {
 
         gpm.map_trampoline();
 
-        // map qemu test
-        if let Some(test) = &guest_machine.test_finisher_address {
-            gpm.push(
-                MapArea::new(
-                    test.base_address.into(),
-                    (test.base_address + test.size).into(),
-                    Some(test.base_address.into()),
-                    Some((test.base_address + test.size).into()),
-                    MapType::Linear,
-                    MapPermission::R | MapPermission::W | MapPermission::U,
-                ),
-                None,
-            );
-        }
+        // qemu test finisher is emulated by hypervisor, so that guest
+        // can only power off or reset itself
 
         // map virtio device
         for virtio_dev in guest_machine.virtio.iter() {
@@ -361,33 +363,7 @@ impl<G: GuestPageTable> GuestMemorySet<G> {
             );
         }
 
-        if let Some(clint) = &guest_machine.clint {
-            gpm.push(
-                MapArea::new(
-                    clint.base_address.into(),
-                    (clint.base_address + clint.size).into(),
-                    Some(clint.base_address.into()),
-                    Some((clint.base_address + clint.size).into()),
-                    MapType::Linear,
-                    MapPermission::R | MapPermission::W | MapPermission::U,
-                ),
-                None,
-            );
-        }
-
-        if let Some(plic) = &guest_machine.plic {
-            gpm.push(
-                MapArea::new(
-                    plic.base_address.into(),
-                    (plic.base_address).into(),
-                    Some(plic.base_address.into()),
-                    Some((plic.base_address).into()),
-                    MapType::Linear,
-                    MapPermission::R | MapPermission::W | MapPermission::U,
-                ),
-                None,
-            );
-        }
+        // clint and plic are emulated by hypervisor, all accesses trap
 
         gpm
     }
@@ -423,13 +399,14 @@ impl<G: GuestPageTable> GuestMemorySet<G> {
 
         gpm.map_trampoline();
 
-        // map qemu test
+        // qemu test finisher is emulated by hypervisor, so that guest
+        // can only power off or reset itself, map the rtc behind it
         if let Some(test) = &guest_machine.test_finisher_address {
             gpm.push(
                 MapArea::new(
-                    test.base_address.into(),
+                    (test.base_address + test.size).into(),
                     (test.base_address + test.size + 0x1000).into(),
-                    Some(test.base_address.into()),
+                    Some((test.base_address + test.size).into()),
                     Some((test.base_address + test.size + 0x1000).into()),
                     MapType::Linear,
                     MapPermission::R | MapPermission::W | MapPermission::U | MapPermission::X,
@@ -467,33 +444,7 @@ impl<G: GuestPageTable> GuestMemorySet<G> {
             );
         }
 
-        if let Some(clint) = &guest_machine.clint {
-            gpm.push(
-                MapArea::new(
-                    clint.base_address.into(),
-                    (clint.base_address + clint.size).into(),
-                    Some(clint.base_address.into()),
-                    Some((clint.base_address + clint.size).into()),
-                    MapType::Linear,
-                    MapPermission::R | MapPermission::W | MapPermission::U,
-                ),
-                None,
-            );
-        }
-
-        if let Some(plic) = &guest_machine.plic {
-            gpm.push(
-                MapArea::new(
-                    plic.base_address.into(),
-                    (plic.base_address + 0x0020_0000).into(),
-                    Some(plic.base_address.into()),
-                    Some((plic.base_address + 0x0020_0000).into()),
-                    MapType::Linear,
-                    MapPermission::R | MapPermission::W | MapPermission::U,
-                ),
-                None,
-            );
-        }
+        // clint and plic are emulated by hypervisor, all accesses trap
 
         if let Some(pci) = &guest_machine.pci {
             gpm.push(
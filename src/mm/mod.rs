@@ -1,8 +1,7 @@
 mod memory_set;
 
-pub use memory_set::{HostMemorySet, GuestMemorySet, MapArea, remap_test, MapPermission};
+pub use memory_set::{HostMemorySet, GuestMemorySet, MapArea, MapType, remap_test, MapPermission};
 
-use memory_set::MapType;
 use crate::guest::page_table::GuestPageTable;
 use crate::page_table::{VirtAddr, PageTable, VirtPageNum, PageTableEntry, PhysAddr, PTEFlags};
 use crate::constants::layout::TRAMPOLINE;
@@ -9,6 +9,12 @@ pub const SBI_CONSOLE_GETCHAR: usize = 2;
 
 pub mod leagcy {
     pub const SBI_SET_TIMER: usize = 0;
+    pub const SBI_CLEAR_IPI: usize = 3;
+    pub const SBI_SEND_IPI: usize = 4;
+    pub const SBI_REMOTE_FENCE_I: usize = 5;
+    pub const SBI_REMOTE_SFENCE_VMA: usize = 6;
+    pub const SBI_REMOTE_SFENCE_VMA_ASID: usize = 7;
+    pub const SBI_SHUTDOWN: usize = 8;
 }
 
 pub const SBI_SUCCESS: usize = 0;
@@ -18,6 +24,8 @@ pub const SBI_ERR_INAVLID_PARAM: isize = -3;
 pub const SBI_ERR_DENIED: isize = -4;
 pub const SBI_ERR_INVALID_ADDRESS: isize = -5;
 pub const SBI_ERR_ALREADY_AVAILABLE: isize = -6; 
+pub const SBI_ERR_ALREADY_STARTED: isize = -7;
+pub const SBI_ERR_ALREADY_STOPPED: isize = -8;
 
 pub const SBI_EXTID_BASE: usize = 0x10;
 pub const SBI_GET_SBI_SPEC_VERSION_FID: usize = 0;
@@ -38,6 +46,20 @@ pub const SBI_EXTID_HSM: usize = 0x48534D;
 pub const SBI_HART_START_FID: usize = 0;
 pub const SBI_HART_STOP_FID: usize = 1;
 pub const SBI_HART_STATUS_FID: usize = 2;
+pub const SBI_HART_SUSPEND_FID: usize = 3;
+pub const SBI_HSM_SUSPEND_RETENTIVE: usize = 0x0000_0000;
+pub const SBI_HSM_SUSPEND_NON_RETENTIVE: usize = 0x8000_0000;
+
+pub const SBI_EXTID_SRST: usize = 0x53525354;
+pub const SBI_SYSTEM_RESET_FID: usize = 0;
+pub const SBI_RESET_TYPE_SHUTDOWN: usize = 0;
+pub const SBI_RESET_TYPE_COLD_REBOOT: usize = 1;
+pub const SBI_RESET_TYPE_WARM_REBOOT: usize = 2;
+
+pub const SBI_EXTID_DBCN: usize = 0x4442434E;
+pub const SBI_DBCN_CONSOLE_WRITE_FID: usize = 0;
+pub const SBI_DBCN_CONSOLE_READ_FID: usize = 1;
+pub const SBI_DBCN_CONSOLE_WRITE_BYTE_FID: usize = 2;
 
 pub const SBI_EXTID_RFNC: usize = 0x52464E43;
 pub const SBI_REMOTE_FENCE_I_FID: usize = 0;
@@ -48,6 +70,35 @@ pub const SBI_REMOTE_HFENCE_GVMA_VMID_FID: usize = 4;
 pub const SBI_REMOTE_HFENCE_VVMA_FIDL: usize = 5;
 pub const SBI_REMOTE_HFENCE_VVMA_ASID_FID: usize = 6;
 
+pub const SBI_EXTID_PMU: usize = 0x504D55;
+pub const SBI_PMU_NUM_COUNTERS_FID: usize = 0;
+pub const SBI_PMU_COUNTER_GET_INFO_FID: usize = 1;
+pub const SBI_PMU_COUNTER_CFG_MATCH_FID: usize = 2;
+pub const SBI_PMU_COUNTER_START_FID: usize = 3;
+pub const SBI_PMU_COUNTER_STOP_FID: usize = 4;
+pub const SBI_PMU_COUNTER_FW_READ_FID: usize = 5;
+pub const SBI_PMU_CFG_FLAG_SKIP_MATCH: usize = 1 << 0;
+pub const SBI_PMU_CFG_FLAG_CLEAR_VALUE: usize = 1 << 1;
+pub const SBI_PMU_CFG_FLAG_AUTO_START: usize = 1 << 2;
+pub const SBI_PMU_START_FLAG_SET_INIT_VALUE: usize = 1 << 0;
+pub const SBI_PMU_STOP_FLAG_RESET: usize = 1 << 0;
+/// counter type bit of `counter_get_info`, set for firmware counters
+pub const SBI_PMU_COUNTER_INFO_FIRMWARE: usize = 1 << 63;
+pub const SBI_PMU_EVENT_TYPE_FIRMWARE: usize = 0xf;
+pub const SBI_PMU_FW_MISALIGNED_LOAD: usize = 0;
+pub const SBI_PMU_FW_MISALIGNED_STORE: usize = 1;
+pub const SBI_PMU_FW_SET_TIMER: usize = 5;
+pub const SBI_PMU_FW_IPI_SENT: usize = 6;
+pub const SBI_PMU_FW_IPI_RECEIVED: usize = 7;
+pub const SBI_PMU_FW_FENCE_I_SENT: usize = 8;
+pub const SBI_PMU_FW_SFENCE_VMA_SENT: usize = 10;
+pub const SBI_PMU_FW_SFENCE_VMA_ASID_SENT: usize = 12;
+/// platform specific firmware event, selected by `event_data`
+pub const SBI_PMU_FW_PLATFORM: usize = 0xffff;
+
+pub const SBI_EXTID_STA: usize = 0x535441;
+pub const SBI_STA_SET_SHMEM_FID: usize = 0;
+
 
 #[inline(always)]
 /// general sbi call
@@ -67,6 +118,44 @@ fn sbi_call(which: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
 }
 
 
+#[inline(always)]
+/// sbi call with extension id and function id, return sbi error code
+fn sbi_call_ext(eid: usize, fid: usize, arg0: usize, arg1: usize, arg2: usize, arg3: usize, arg4: usize) -> usize {
+    let mut error;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("x10") arg0 => error,
+            inlateout("x11") arg1 => _,
+            in("x12") arg2,
+            in("x13") arg3,
+            in("x14") arg4,
+            in("x16") fid,
+            in("x17") eid,
+        );
+    }
+    error
+}
+
+#[inline(always)]
+/// sbi call with extension id and function id, return (error, value)
+fn sbi_call_ext_value(eid: usize, fid: usize, arg0: usize, arg1: usize, arg2: usize, arg3: usize, arg4: usize) -> (usize, usize) {
+    let (error, value);
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("x10") arg0 => error,
+            inlateout("x11") arg1 => value,
+            in("x12") arg2,
+            in("x13") arg3,
+            in("x14") arg4,
+            in("x16") fid,
+            in("x17") eid,
+        );
+    }
+    (error, value)
+}
+
 /// use sbi call to putchar in console (qemu uart handler)
 pub fn console_putchar(c: usize) {
     sbi_call(SBI_CONSOLE_PUTCHAR, c, 0, 0);
@@ -87,4 +176,37 @@ pub fn shutdown() -> ! {
     unreachable!()
 }
 
+/// use sbi call to power off the machine when no guest is left
+pub fn poweroff() -> ! {
+    sbi_rt::system_reset(sbi_rt::Shutdown, sbi_rt::NoReason);
+    unreachable!()
+}
+
+/// instruct remote harts to execute `fence.i`
+pub fn remote_fence_i(hart_mask: usize, hart_mask_base: usize) -> usize {
+    sbi_call_ext(SBI_EXTID_RFNC, SBI_REMOTE_FENCE_I_FID, hart_mask, hart_mask_base, 0, 0, 0)
+}
 
+/// number of firmware PMU counters, 0 if PMU extension is unavailable
+pub fn pmu_num_counters() -> usize {
+    let (error, value) = sbi_call_ext_value(SBI_EXTID_PMU, SBI_PMU_NUM_COUNTERS_FID, 0, 0, 0, 0, 0);
+    if error == SBI_SUCCESS { value } else { 0 }
+}
+
+/// return (error, counter info)
+pub fn pmu_counter_get_info(counter_idx: usize) -> (usize, usize) {
+    sbi_call_ext_value(SBI_EXTID_PMU, SBI_PMU_COUNTER_GET_INFO_FID, counter_idx, 0, 0, 0, 0)
+}
+
+/// return (error, counter index)
+pub fn pmu_counter_config_matching(counter_idx_base: usize, counter_idx_mask: usize, config_flags: usize, event_idx: usize, event_data: usize) -> (usize, usize) {
+    sbi_call_ext_value(SBI_EXTID_PMU, SBI_PMU_COUNTER_CFG_MATCH_FID, counter_idx_base, counter_idx_mask, config_flags, event_idx, event_data)
+}
+
+pub fn pmu_counter_start(counter_idx_base: usize, counter_idx_mask: usize, start_flags: usize, initial_value: usize) -> usize {
+    sbi_call_ext(SBI_EXTID_PMU, SBI_PMU_COUNTER_START_FID, counter_idx_base, counter_idx_mask, start_flags, initial_value, 0)
+}
+
+pub fn pmu_counter_stop(counter_idx_base: usize, counter_idx_mask: usize, stop_flags: usize) -> usize {
+    sbi_call_ext(SBI_EXTID_PMU, SBI_PMU_COUNTER_STOP_FID, counter_idx_base, counter_idx_mask, stop_flags, 0, 0)
+}
@@ -0,0 +1,170 @@
+//! Per hart timer queue. Deadlines of all vcpus bound to the hart and the
+//! scheduler tick of hypervisor share one host timer, which is always
+//! programmed with the earliest deadline.
+
+use alloc::vec::Vec;
+
+use riscv::register::{sie, time};
+
+use crate::constants::CLOCK_FREQ;
+use crate::guest::page_table::GuestPageTable;
+use crate::guest::{VCpuState, VCPU_EVENT_TIMER, VCPU_EVENT_WAKE};
+use crate::hypervisor::HostVmm;
+use crate::page_table::PageTable;
+use crate::sbi::set_timer;
+
+/// scheduler tick, 10ms
+pub const SCHED_TICK_INTERVAL: usize = CLOCK_FREQ / 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerEvent {
+    /// timer set by vcpu through SBI
+    VCpu { guest_id: usize, vcpu_id: usize },
+    /// `vstimecmp` of vcpu parked by `wfi` passes(Sstc)
+    Wake { guest_id: usize, vcpu_id: usize },
+    /// time slice of running vcpu is used up
+    SchedTick,
+}
+
+impl TimerEvent {
+    fn guest_id(&self) -> Option<usize> {
+        match *self {
+            TimerEvent::VCpu { guest_id, .. } | TimerEvent::Wake { guest_id, .. } => Some(guest_id),
+            TimerEvent::SchedTick => None,
+        }
+    }
+}
+
+struct TimerEntry {
+    deadline: usize,
+    event: TimerEvent,
+}
+
+/// timer events ordered by deadline
+pub struct TimerQueue {
+    entries: Vec<TimerEntry>,
+    /// timers of guests whose clock is paused
+    parked: Vec<TimerEntry>,
+}
+
+impl TimerQueue {
+    pub fn new() -> Self {
+        Self { entries: Vec::new(), parked: Vec::new() }
+    }
+
+    /// add `event` at `deadline`, replacing the pending one of the same event
+    pub fn set(&mut self, event: TimerEvent, deadline: usize) {
+        self.cancel(event);
+        let pos = self.entries.partition_point(|entry| entry.deadline <= deadline);
+        self.entries.insert(pos, TimerEntry { deadline, event });
+    }
+
+    pub fn cancel(&mut self, event: TimerEvent) {
+        self.entries.retain(|entry| entry.event != event);
+        self.parked.retain(|entry| entry.event != event);
+    }
+
+    /// drop all vcpu timers of guest
+    pub fn cancel_guest(&mut self, guest_id: usize) {
+        self.entries.retain(|entry| entry.event.guest_id() != Some(guest_id));
+        self.parked.retain(|entry| entry.event.guest_id() != Some(guest_id));
+    }
+
+    /// Hold vcpu timers of guest whose clock is paused, so that they do
+    /// not expire while it is descheduled.
+    pub fn park_guest(&mut self, guest_id: usize) {
+        let (parked, entries): (Vec<TimerEntry>, Vec<TimerEntry>) =
+            self.entries.drain(..).partition(|entry| entry.event.guest_id() == Some(guest_id));
+        self.entries = entries;
+        self.parked.extend(parked);
+    }
+
+    /// put back timers of guest held by `park_guest`, postponed by `delay`
+    pub fn unpark_guest(&mut self, guest_id: usize, delay: usize) {
+        let (unparked, parked): (Vec<TimerEntry>, Vec<TimerEntry>) =
+            self.parked.drain(..).partition(|entry| entry.event.guest_id() == Some(guest_id));
+        self.parked = parked;
+        for entry in unparked {
+            self.set(entry.event, entry.deadline.saturating_add(delay));
+        }
+    }
+
+    pub fn next_deadline(&self) -> Option<usize> {
+        self.entries.first().map(|entry| entry.deadline)
+    }
+
+    /// remove and return events whose deadline has passed
+    pub fn pop_expired(&mut self, now: usize) -> Vec<TimerEvent> {
+        let expired = self.entries.partition_point(|entry| entry.deadline <= now);
+        self.entries.drain(..expired).map(|entry| entry.event).collect()
+    }
+}
+
+impl<P: PageTable, G: GuestPageTable> HostVmm<P, G> {
+    /// program host timer with the earliest deadline in timer queue
+    pub fn program_timer(&mut self) {
+        let deadline = self.timer_queue.next_deadline().unwrap_or(usize::MAX);
+        set_timer(deadline);
+        unsafe{ sie::set_stimer() };
+    }
+
+    /// set timer of running vcpu, its pending timer interrupt is cleared
+    pub fn set_vcpu_timer(&mut self, deadline: usize) {
+        let guest_id = self.guest_id;
+        if let Some(guest) = self.guests[guest_id].as_mut() {
+            let vcpu_id = guest.vcpu_id;
+            guest.vcpus[vcpu_id].clear_timer();
+            self.timer_queue.set(TimerEvent::VCpu { guest_id, vcpu_id }, deadline);
+        }
+        self.program_timer();
+    }
+
+    /// set timer of any vcpu of guest, its pending timer interrupt is cleared
+    pub fn set_guest_vcpu_timer(&mut self, guest_id: usize, vcpu_id: usize, deadline: usize) {
+        let running = guest_id == self.guest_id
+            && self.guests[guest_id].as_ref().map_or(false, |guest| guest.vcpu_id == vcpu_id);
+        if running {
+            self.set_vcpu_timer(deadline);
+            return;
+        }
+        if let Some(vcpu) = self.guests[guest_id].as_mut().and_then(|guest| guest.vcpus.get_mut(vcpu_id)) {
+            vcpu.clear_saved_timer();
+            self.timer_queue.set(TimerEvent::VCpu { guest_id, vcpu_id }, deadline);
+        }
+        self.program_timer();
+    }
+
+    /// Deliver expired timer events, `VSTIP` is only injected into vcpus
+    /// whose deadlines passed. Return whether scheduler tick expired.
+    pub fn expire_timers(&mut self) -> bool {
+        let now = time::read();
+        let mut resched = false;
+        for event in self.timer_queue.pop_expired(now) {
+            match event {
+                TimerEvent::VCpu { guest_id, vcpu_id } | TimerEvent::Wake { guest_id, vcpu_id } => {
+                    let vcpu = self.guests[guest_id].as_mut().and_then(|guest| guest.vcpus.get_mut(vcpu_id));
+                    if let Some(vcpu) = vcpu {
+                        if vcpu.state != VCpuState::Stopped {
+                            let wake = matches!(event, TimerEvent::Wake { .. });
+                            vcpu.push_event(if wake { VCPU_EVENT_WAKE } else { VCPU_EVENT_TIMER });
+                        }
+                    }
+                }
+                TimerEvent::SchedTick => resched = true,
+            }
+        }
+        if resched {
+            self.timer_queue.set(TimerEvent::SchedTick, now + SCHED_TICK_INTERVAL);
+        }
+        resched
+    }
+
+    /// deliver expired timer events, reschedule when tick expires
+    pub fn handle_timer_irq(&mut self) {
+        if self.expire_timers() {
+            // give vcpus of other guests a chance to run
+            self.schedule_tick();
+        }
+        self.program_timer();
+    }
+}